@@ -2,6 +2,16 @@
 
 pub mod prelude;
 
+pub mod api_key;
+pub mod audit_log;
 pub mod cake;
+pub mod comment;
+pub mod post_revision;
 pub mod posts;
+pub mod posts_tags;
+pub mod recovery_code;
+pub mod refresh_token;
+pub mod tag;
+pub mod tenant;
 pub mod user;
+pub mod user_token;
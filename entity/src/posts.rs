@@ -2,25 +2,77 @@
 
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize, ToSchema)]
 #[sea_orm(table_name = "posts")]
+#[schema(as = Post)]
 pub struct Model {
     #[sea_orm(primary_key)]
     #[serde(skip_deserializing)]
+    #[schema(read_only)]
     pub id: i32,
     pub title: String,
     pub text: String,
     pub new_col: i32,
+    /// Set from the caller's token, never from the request body
+    #[serde(skip)]
+    pub tenant_id: i32,
 }
 
-#[derive(Copy, Clone, Debug, EnumIter)]
-pub enum Relation {}
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::tenant::Entity",
+        from = "Column::TenantId",
+        to = "super::tenant::Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    Tenant,
+    #[sea_orm(has_many = "super::post_revision::Entity")]
+    PostRevision,
+    #[sea_orm(has_many = "super::posts_tags::Entity")]
+    PostsTags,
+    #[sea_orm(has_many = "super::comment::Entity")]
+    Comment,
+}
+
+impl Related<super::tenant::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Tenant.def()
+    }
+}
+
+impl Related<super::post_revision::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::PostRevision.def()
+    }
+}
+
+impl Related<super::comment::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Comment.def()
+    }
+}
+
+impl Related<super::posts_tags::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::PostsTags.def()
+    }
+}
+
+impl Related<super::tag::Entity> for Entity {
+    fn to() -> RelationDef {
+        super::posts_tags::Relation::Tag.def()
+    }
 
-impl RelationTrait for Relation {
-    fn def(&self) -> RelationDef {
-        panic!("No RelationDef")
+    fn via() -> Option<RelationDef> {
+        Some(super::posts_tags::Relation::Posts.def().rev())
     }
 }
 
 impl ActiveModelBehavior for ActiveModel {}
+
+/// Name of the model in the OpenAPI document
+pub type Post = Model;
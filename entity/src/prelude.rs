@@ -1,5 +1,15 @@
 //! SeaORM Entity. Generated by sea-orm-codegen 0.9.1
 
+pub use super::api_key::Entity as ApiKey;
+pub use super::audit_log::Entity as AuditLog;
 pub use super::cake::Entity as Cake;
+pub use super::comment::Entity as Comment;
+pub use super::post_revision::Entity as PostRevision;
 pub use super::posts::Entity as Posts;
+pub use super::posts_tags::Entity as PostsTags;
+pub use super::recovery_code::Entity as RecoveryCode;
+pub use super::refresh_token::Entity as RefreshToken;
+pub use super::tag::Entity as Tag;
+pub use super::tenant::Entity as Tenant;
 pub use super::user::Entity as User;
+pub use super::user_token::Entity as UserToken;
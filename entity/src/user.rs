@@ -10,14 +10,41 @@ pub struct Model {
     pub id: i32,
     pub email: String,
     pub hash: String,
+    /// Space-separated scopes tokens of this user may carry
+    pub scopes: String,
+    pub tenant_id: i32,
+    pub email_verified_at: Option<DateTimeUtc>,
+    /// Base32 TOTP secret, pending until `totp_enabled_at` is set
+    #[serde(skip)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<DateTimeUtc>,
+    /// Last time step a code was accepted for, so codes can't be replayed
+    pub totp_last_step: Option<i64>,
 }
 
-#[derive(Copy, Clone, Debug, EnumIter)]
-pub enum Relation {}
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::tenant::Entity",
+        from = "Column::TenantId",
+        to = "super::tenant::Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    Tenant,
+    #[sea_orm(has_many = "super::comment::Entity")]
+    Comment,
+}
+
+impl Related<super::tenant::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Tenant.def()
+    }
+}
 
-impl RelationTrait for Relation {
-    fn def(&self) -> RelationDef {
-        panic!("No RelationDef")
+impl Related<super::comment::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Comment.def()
     }
 }
 
@@ -5,6 +5,16 @@ mod m20220819_220330_create_cake;
 mod m20220820_000001_alter_post_table;
 mod m20220902_151527_create_user_table;
 mod m20220902_153021_seeding_user_table_data;
+mod m20221003_000001_create_refresh_token_table;
+mod m20221010_000001_add_scopes_to_user;
+mod m20221017_000001_create_api_key_table;
+mod m20221024_000001_create_tenant_table;
+mod m20221031_000001_create_user_token_table;
+mod m20221107_000001_add_totp_to_user;
+mod m20221114_000001_create_audit_log_table;
+mod m20221121_000001_create_post_revision_table;
+mod m20221128_000001_create_tag_tables;
+mod m20221205_000001_create_comment_table;
 
 pub struct Migrator;
 
@@ -17,6 +27,16 @@ impl MigratorTrait for Migrator {
             Box::new(m20220820_000001_alter_post_table::Migration),
             Box::new(m20220902_151527_create_user_table::Migration),
             Box::new(m20220902_153021_seeding_user_table_data::Migration),
+            Box::new(m20221003_000001_create_refresh_token_table::Migration),
+            Box::new(m20221010_000001_add_scopes_to_user::Migration),
+            Box::new(m20221017_000001_create_api_key_table::Migration),
+            Box::new(m20221024_000001_create_tenant_table::Migration),
+            Box::new(m20221031_000001_create_user_token_table::Migration),
+            Box::new(m20221107_000001_add_totp_to_user::Migration),
+            Box::new(m20221114_000001_create_audit_log_table::Migration),
+            Box::new(m20221121_000001_create_post_revision_table::Migration),
+            Box::new(m20221128_000001_create_tag_tables::Migration),
+            Box::new(m20221205_000001_create_comment_table::Migration),
         ]
     }
 }
@@ -1,33 +1,20 @@
-use entity::user;
-use sea_orm::DeleteResult;
 use sea_orm_migration::prelude::*;
-use sea_orm_migration::sea_orm::entity::*;
 
+/// Formerly inserted the `account@example.com` demo user.
+///
+/// Seed data now lives in the server's `seeder` module and `fixtures/`, so
+/// schema migrations never create accounts. The migration is kept as a no-op
+/// because databases that already applied it record its name.
 #[derive(DeriveMigrationName)]
 pub struct Migration;
 
 #[async_trait::async_trait]
 impl MigrationTrait for Migration {
-    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
-        // Replace the sample below with your own migration scripts
-        let db = manager.get_connection();
-        user::ActiveModel {
-            email: Set("account@example.com".to_owned()),
-            hash: Set("cLVE7E3Y71+ng0/laMdt9fPPdbb93vE9eeJCjoda21s=".to_owned()), // "secret"
-            ..Default::default()
-        }
-        .insert(db)
-        .await?;
-
+    async fn up(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
         Ok(())
     }
 
-    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
-        // Replace the sample below with your own migration scripts
-        let db = manager.get_connection();
-        let res: DeleteResult = entity::user::Entity::delete_many().exec(db).await?;
-        assert!(res.rows_affected > 0);
-
+    async fn down(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
         Ok(())
     }
 }
@@ -0,0 +1,68 @@
+use sea_orm_migration::prelude::*;
+
+/// Refresh tokens issued by `/oauth/token`, stored hashed.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(RefreshToken::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(RefreshToken::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(RefreshToken::UserId).integer().not_null())
+                    .col(
+                        ColumnDef::new(RefreshToken::Hash)
+                            .string()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(RefreshToken::Scope).string().not_null())
+                    .col(
+                        ColumnDef::new(RefreshToken::ExpiresAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-refresh_token-user_id")
+                            .from(RefreshToken::Table, RefreshToken::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(RefreshToken::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum RefreshToken {
+    Table,
+    Id,
+    UserId,
+    Hash,
+    Scope,
+    ExpiresAt,
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
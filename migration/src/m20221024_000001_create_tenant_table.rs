@@ -0,0 +1,184 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, DatabaseBackend};
+
+/// Tenants (companies) owning users and posts.
+///
+/// Rows that predate tenants are moved to the first tenant, named after the
+/// `ACME` company tokens used to carry. SQLite can't add a column with a
+/// foreign key and a non-null default, so there `tenant_id` is added nullable
+/// with the reference and filled in afterwards; the other backends add it with
+/// the default and then the foreign key.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Tenant::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Tenant::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(Tenant::Name)
+                            .string()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        let insert = Query::insert()
+            .into_table(Tenant::Table)
+            .columns([Tenant::Name])
+            .values_panic(["ACME".into()])
+            .to_owned();
+        let backend = manager.get_database_backend();
+        let conn = manager.get_connection();
+        conn.execute(backend.build(&insert)).await?;
+        let select = Query::select()
+            .column(Tenant::Id)
+            .from(Tenant::Table)
+            .and_where(Expr::col(Tenant::Name).eq("ACME"))
+            .to_owned();
+        let acme: i32 = conn
+            .query_one(backend.build(&select))
+            .await?
+            .ok_or_else(|| DbErr::Custom("ACME tenant was not inserted".to_owned()))?
+            .try_get("", &Tenant::Id.to_string())?;
+
+        add_tenant_column(manager, User::Table, acme).await?;
+        add_tenant_column(manager, Posts::Table, acme).await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-posts-tenant_id")
+                    .table(Posts::Table)
+                    .col(TenantId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-posts-tenant_id")
+                    .table(Posts::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Posts::Table)
+                    .drop_column(TenantId) // sqlite not support drop column
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(TenantId)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Tenant::Table).to_owned())
+            .await
+    }
+}
+
+/// Add `tenant_id` referencing `tenant` to `table`, set to `acme` in the
+/// existing rows.
+async fn add_tenant_column<T>(manager: &SchemaManager<'_>, table: T, acme: i32) -> Result<(), DbErr>
+where
+    T: Iden + Copy + 'static,
+{
+    let mut column = ColumnDef::new(TenantId);
+    column.integer();
+    if manager.get_database_backend() == DatabaseBackend::Sqlite {
+        column.extra(format!(
+            "REFERENCES {} ({})",
+            quoted(manager, Tenant::Table),
+            quoted(manager, Tenant::Id)
+        ));
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(table)
+                    .add_column(&mut column)
+                    .to_owned(),
+            )
+            .await?;
+        let update = Query::update()
+            .table(table)
+            .value(TenantId, acme.into())
+            .to_owned();
+        let stmt = manager.get_database_backend().build(&update);
+        manager.get_connection().execute(stmt).await?;
+        return Ok(());
+    }
+
+    manager
+        .alter_table(
+            Table::alter()
+                .table(table)
+                .add_column(column.not_null().default(acme))
+                .to_owned(),
+        )
+        .await?;
+    manager
+        .alter_table(
+            Table::alter()
+                .table(table)
+                .add_foreign_key(
+                    TableForeignKey::new()
+                        .name(&format!("fk-{}-tenant_id", table.to_string()))
+                        .from_tbl(table)
+                        .from_col(TenantId)
+                        .to_tbl(Tenant::Table)
+                        .to_col(Tenant::Id),
+                )
+                .to_owned(),
+        )
+        .await
+}
+
+fn quoted(manager: &SchemaManager, iden: impl Iden) -> String {
+    let mut quoted = String::new();
+    iden.prepare(
+        &mut quoted,
+        manager.get_database_backend().get_query_builder().quote(),
+    );
+    quoted
+}
+
+#[derive(Iden)]
+struct TenantId;
+
+#[derive(Iden)]
+enum Tenant {
+    Table,
+    Id,
+    Name,
+}
+
+#[derive(Iden, Clone, Copy)]
+enum User {
+    Table,
+}
+
+#[derive(Iden, Clone, Copy)]
+enum Posts {
+    Table,
+}
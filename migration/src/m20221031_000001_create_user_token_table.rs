@@ -0,0 +1,86 @@
+use sea_orm_migration::prelude::*;
+
+/// Single-use tokens mailed to users, stored hashed, and the time each user
+/// verified their email.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(UserToken::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(UserToken::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(UserToken::UserId).integer().not_null())
+                    .col(ColumnDef::new(UserToken::Purpose).string().not_null())
+                    .col(
+                        ColumnDef::new(UserToken::Hash)
+                            .string()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(UserToken::ExpiresAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-user_token-user_id")
+                            .from(UserToken::Table, UserToken::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(ColumnDef::new(User::EmailVerifiedAt).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(User::EmailVerifiedAt) // sqlite not support drop column
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_table(Table::drop().table(UserToken::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum UserToken {
+    Table,
+    Id,
+    UserId,
+    Purpose,
+    Hash,
+    ExpiresAt,
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+    EmailVerifiedAt,
+}
@@ -0,0 +1,87 @@
+use sea_orm_migration::prelude::*;
+
+/// TOTP secrets of users and their hashed recovery codes.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // sqlite only adds one column per statement
+        for mut column in [
+            ColumnDef::new(User::TotpSecret).string().to_owned(),
+            ColumnDef::new(User::TotpEnabledAt)
+                .timestamp_with_time_zone()
+                .to_owned(),
+            ColumnDef::new(User::TotpLastStep).big_integer().to_owned(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        manager
+            .create_table(
+                Table::create()
+                    .table(RecoveryCode::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(RecoveryCode::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(RecoveryCode::UserId).integer().not_null())
+                    .col(ColumnDef::new(RecoveryCode::Hash).string().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-recovery_code-user_id")
+                            .from(RecoveryCode::Table, RecoveryCode::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(RecoveryCode::Table).to_owned())
+            .await?;
+        for column in [User::TotpSecret, User::TotpEnabledAt, User::TotpLastStep] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .drop_column(column) // sqlite not support drop column
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum RecoveryCode {
+    Table,
+    Id,
+    UserId,
+    Hash,
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+    TotpSecret,
+    TotpEnabledAt,
+    TotpLastStep,
+}
@@ -0,0 +1,78 @@
+use sea_orm_migration::prelude::*;
+
+/// Who created, updated or deleted which post, user or cake, and when.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AuditLog::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AuditLog::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AuditLog::Actor).string())
+                    .col(ColumnDef::new(AuditLog::TenantId).integer())
+                    .col(ColumnDef::new(AuditLog::Action).string().not_null())
+                    .col(ColumnDef::new(AuditLog::EntityType).string().not_null())
+                    .col(ColumnDef::new(AuditLog::EntityId).integer().not_null())
+                    .col(ColumnDef::new(AuditLog::Before).json())
+                    .col(ColumnDef::new(AuditLog::After).json())
+                    .col(
+                        ColumnDef::new(AuditLog::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audit_log-entity")
+                    .table(AuditLog::Table)
+                    .col(AuditLog::EntityType)
+                    .col(AuditLog::EntityId)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audit_log-created_at")
+                    .table(AuditLog::Table)
+                    .col(AuditLog::CreatedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AuditLog::Table).to_owned())
+            .await
+    }
+}
+
+/// Entries outlive what they describe, so there are no foreign keys.
+#[derive(Iden)]
+enum AuditLog {
+    Table,
+    Id,
+    Actor,
+    TenantId,
+    Action,
+    EntityType,
+    EntityId,
+    Before,
+    After,
+    CreatedAt,
+}
@@ -0,0 +1,80 @@
+use sea_orm_migration::prelude::*;
+
+/// Earlier versions of posts, one per update.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PostRevision::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PostRevision::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(PostRevision::PostId).integer().not_null())
+                    .col(ColumnDef::new(PostRevision::Number).integer().not_null())
+                    .col(ColumnDef::new(PostRevision::Title).string().not_null())
+                    .col(ColumnDef::new(PostRevision::Text).string().not_null())
+                    .col(ColumnDef::new(PostRevision::NewCol).integer().not_null())
+                    .col(ColumnDef::new(PostRevision::ReplacedBy).string().not_null())
+                    .col(
+                        ColumnDef::new(PostRevision::ReplacedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-post_revision-post_id")
+                            .from(PostRevision::Table, PostRevision::PostId)
+                            .to(Posts::Table, Posts::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-post_revision-post_id-number")
+                    .table(PostRevision::Table)
+                    .col(PostRevision::PostId)
+                    .col(PostRevision::Number)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PostRevision::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum PostRevision {
+    Table,
+    Id,
+    PostId,
+    Number,
+    Title,
+    Text,
+    NewCol,
+    ReplacedBy,
+    ReplacedAt,
+}
+
+#[derive(Iden)]
+enum Posts {
+    Table,
+    Id,
+}
@@ -0,0 +1,116 @@
+use sea_orm_migration::prelude::*;
+
+/// Tags of each tenant and the join table tagging posts with them.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Tag::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Tag::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Tag::TenantId).integer().not_null())
+                    .col(ColumnDef::new(Tag::Name).string().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-tag-tenant_id")
+                            .from(Tag::Table, Tag::TenantId)
+                            .to(Tenant::Table, Tenant::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-tag-tenant_id-name")
+                    .table(Tag::Table)
+                    .col(Tag::TenantId)
+                    .col(Tag::Name)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(PostsTags::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(PostsTags::PostId).integer().not_null())
+                    .col(ColumnDef::new(PostsTags::TagId).integer().not_null())
+                    .primary_key(Index::create().col(PostsTags::PostId).col(PostsTags::TagId))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-posts_tags-post_id")
+                            .from(PostsTags::Table, PostsTags::PostId)
+                            .to(Posts::Table, Posts::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-posts_tags-tag_id")
+                            .from(PostsTags::Table, PostsTags::TagId)
+                            .to(Tag::Table, Tag::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-posts_tags-tag_id")
+                    .table(PostsTags::Table)
+                    .col(PostsTags::TagId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PostsTags::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Tag::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum Tag {
+    Table,
+    Id,
+    TenantId,
+    Name,
+}
+
+#[derive(Iden)]
+enum PostsTags {
+    Table,
+    PostId,
+    TagId,
+}
+
+#[derive(Iden)]
+enum Posts {
+    Table,
+    Id,
+}
+
+#[derive(Iden)]
+enum Tenant {
+    Table,
+    Id,
+}
@@ -0,0 +1,91 @@
+use sea_orm_migration::prelude::*;
+
+/// Comments of users on posts, deleted with either.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Comment::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Comment::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Comment::PostId).integer().not_null())
+                    .col(ColumnDef::new(Comment::AuthorId).integer().not_null())
+                    .col(ColumnDef::new(Comment::Body).text().not_null())
+                    .col(
+                        ColumnDef::new(Comment::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(Comment::UpdatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-comment-post_id")
+                            .from(Comment::Table, Comment::PostId)
+                            .to(Posts::Table, Posts::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-comment-author_id")
+                            .from(Comment::Table, Comment::AuthorId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-comment-post_id")
+                    .table(Comment::Table)
+                    .col(Comment::PostId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Comment::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum Comment {
+    Table,
+    Id,
+    PostId,
+    AuthorId,
+    Body,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(Iden)]
+enum Posts {
+    Table,
+    Id,
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
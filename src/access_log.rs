@@ -0,0 +1,275 @@
+//! One access log line per request, plus per-request database timings.
+//!
+//! - `ACCESS_LOG_FORMAT` is `default` (structured fields), `common`,
+//!   `combined` or `off`
+//! - `ACCESS_LOG_SAMPLE_RATE` between `0.0` and `1.0` (default `1.0`); server
+//!   errors are always logged
+//!
+//! Lines are emitted with the `access_log` target inside the request span, so
+//! they carry the request id like every other event of the request.
+
+use std::cell::Cell;
+use std::env;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header, HeaderMap, HeaderName, Request},
+    middleware::Next,
+    response::Response,
+};
+use lazy_static::lazy_static;
+use sea_orm::DatabaseConnection;
+
+lazy_static! {
+    static ref CONFIG: AccessLogConfig = AccessLogConfig::from_env();
+    static ref SAMPLER: Sampler = Sampler::new(CONFIG.sample_rate);
+}
+
+tokio::task_local! {
+    static DB_TIMINGS: DbTimings;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    Default,
+    Common,
+    Combined,
+    Off,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "default" => Ok(AccessLogFormat::Default),
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "off" => Ok(AccessLogFormat::Off),
+            other => Err(format!("unknown access log format {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AccessLogConfig {
+    format: AccessLogFormat,
+    sample_rate: f64,
+}
+
+impl AccessLogConfig {
+    fn from_env() -> Self {
+        let format = env::var("ACCESS_LOG_FORMAT")
+            .ok()
+            .map(|format| {
+                format
+                    .parse()
+                    .expect("ACCESS_LOG_FORMAT must be default, common, combined or off")
+            })
+            .unwrap_or(AccessLogFormat::Default);
+        let sample_rate = env::var("ACCESS_LOG_SAMPLE_RATE")
+            .ok()
+            .map(|rate| {
+                rate.parse::<f64>()
+                    .expect("ACCESS_LOG_SAMPLE_RATE must be a number")
+            })
+            .unwrap_or(1.0)
+            .clamp(0.0, 1.0);
+
+        Self {
+            format,
+            sample_rate,
+        }
+    }
+}
+
+/// Deterministic sampling: of every `n` requests, `n * rate` are logged,
+/// spread evenly.
+struct Sampler {
+    rate: f64,
+    counter: AtomicU64,
+}
+
+impl Sampler {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    fn sample(&self) -> bool {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.rate).floor() > (n * self.rate).floor()
+    }
+}
+
+#[derive(Default)]
+struct DbTimings {
+    queries: Cell<u32>,
+    elapsed: Cell<Duration>,
+}
+
+/// Fields of one access log line.
+struct Entry {
+    remote_addr: Option<SocketAddr>,
+    time: String,
+    method: String,
+    uri: String,
+    version: String,
+    status: u16,
+    bytes: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    fn common(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            self.remote_addr
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            self.time,
+            self.method,
+            self.uri,
+            self.version,
+            self.status,
+            self.bytes.as_deref().unwrap_or("-"),
+        )
+    }
+
+    fn combined(&self) -> String {
+        format!(
+            "{} \"{}\" \"{}\"",
+            self.common(),
+            self.referer.as_deref().unwrap_or("-"),
+            self.user_agent.as_deref().unwrap_or("-"),
+        )
+    }
+}
+
+/// Report the duration of every statement to the request being served.
+pub fn instrument(conn: &mut DatabaseConnection) {
+    conn.set_metric_callback(|info| {
+        let _ = DB_TIMINGS.try_with(|timings| {
+            timings.queries.set(timings.queries.get() + 1);
+            timings.elapsed.set(timings.elapsed.get() + info.elapsed);
+        });
+        // Only the SQL with placeholders: bound values may hold secrets
+        tracing::debug!(
+            target: "db",
+            elapsed_ms = info.elapsed.as_secs_f64() * 1000.0,
+            failed = info.failed,
+            sql = %info.statement.sql,
+            "query"
+        );
+    });
+}
+
+pub async fn access_log<B>(req: Request<B>, next: Next<B>) -> Response {
+    let config = &*CONFIG;
+    if config.format == AccessLogFormat::Off {
+        return next.run(req).await;
+    }
+
+    let mut entry = Entry {
+        remote_addr: req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr),
+        time: chrono::Local::now()
+            .format("%d/%b/%Y:%H:%M:%S %z")
+            .to_string(),
+        method: req.method().to_string(),
+        uri: req.uri().to_string(),
+        version: format!("{:?}", req.version()),
+        status: 0,
+        bytes: None,
+        referer: header_value(req.headers(), header::REFERER),
+        user_agent: header_value(req.headers(), header::USER_AGENT),
+    };
+
+    let start = Instant::now();
+    let (response, queries, db_elapsed) = DB_TIMINGS
+        .scope(DbTimings::default(), async move {
+            let response = next.run(req).await;
+            let (queries, elapsed) =
+                DB_TIMINGS.with(|timings| (timings.queries.get(), timings.elapsed.get()));
+            (response, queries, elapsed)
+        })
+        .await;
+    let latency = start.elapsed();
+
+    entry.status = response.status().as_u16();
+    entry.bytes = header_value(response.headers(), header::CONTENT_LENGTH);
+
+    if !response.status().is_server_error() && !SAMPLER.sample() {
+        return response;
+    }
+    match config.format {
+        AccessLogFormat::Common => tracing::info!(target: "access_log", "{}", entry.common()),
+        AccessLogFormat::Combined => tracing::info!(target: "access_log", "{}", entry.combined()),
+        _ => tracing::info!(
+            target: "access_log",
+            method = %entry.method,
+            uri = %entry.uri,
+            status = entry.status,
+            latency_ms = latency.as_secs_f64() * 1000.0,
+            db_queries = queries,
+            db_ms = db_elapsed.as_secs_f64() * 1000.0,
+            "request completed"
+        ),
+    }
+
+    response
+}
+
+fn header_value(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_rate() {
+        let sampler = Sampler::new(0.25);
+        let sampled = (0..100).filter(|_| sampler.sample()).count();
+        assert_eq!(sampled, 25);
+
+        let sampler = Sampler::new(0.0);
+        assert!(!(0..100).any(|_| sampler.sample()));
+    }
+
+    #[test]
+    fn common_and_combined_lines() {
+        let entry = Entry {
+            remote_addr: Some("127.0.0.1:50000".parse().unwrap()),
+            time: "10/Oct/2000:13:55:36 -0700".to_owned(),
+            method: "GET".to_owned(),
+            uri: "/api/?page=1".to_owned(),
+            version: "HTTP/1.1".to_owned(),
+            status: 200,
+            bytes: Some("2326".to_owned()),
+            referer: None,
+            user_agent: Some("curl/7.64.1".to_owned()),
+        };
+        assert_eq!(
+            entry.common(),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET /api/?page=1 HTTP/1.1" 200 2326"#
+        );
+        assert_eq!(
+            entry.combined(),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET /api/?page=1 HTTP/1.1" 200 2326 "-" "curl/7.64.1""#
+        );
+    }
+}
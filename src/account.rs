@@ -0,0 +1,515 @@
+//! Password reset and email verification.
+//!
+//! Both flows mail the user a random token and take it back at a `/confirm`
+//! endpoint. Tokens are stored hashed in `user_token`, work once and expire:
+//! reset tokens after an hour, verification tokens after a day. Asking for a
+//! new token replaces the previous one of the same kind.
+//!
+//! `POST /password-reset` answers the same whether or not the email belongs to
+//! a user, and as fast, so it can't be used to find accounts. Resetting the
+//! secret also revokes the user's refresh tokens and API keys.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{ConnectInfo, Extension},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use entity::api_key::{self, Entity as ApiKey};
+use entity::refresh_token::{self, Entity as RefreshToken};
+use entity::user::{self, Entity as User};
+use entity::user_token::{self, Entity as UserToken};
+use ring::rand::{SecureRandom, SystemRandom};
+use sea_orm::{prelude::*, ConnectionTrait, Set, TransactionTrait};
+use serde::Deserialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::audit;
+use crate::mailer::{DynMailer, Email};
+use crate::post_service::{hash_secret, AuthError, Claims, FlashData};
+use crate::rate_limit::{self, RateLimiter};
+
+const RESET_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const VERIFICATION_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Purpose {
+    PasswordReset,
+    EmailVerification,
+    /// Second step of logging in, see `mfa`
+    MfaChallenge,
+}
+
+impl Purpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Purpose::PasswordReset => "password_reset",
+            Purpose::EmailVerification => "email_verification",
+            Purpose::MfaChallenge => "mfa_challenge",
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ResetRequest {
+    email: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ResetConfirmation {
+    /// Token from the email
+    token: String,
+    /// New secret
+    secret: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct VerificationConfirmation {
+    /// Token from the email
+    token: String,
+}
+
+// curl -H 'Content-Type: application/json' http://localhost:8000/password-reset --data '{"email":"account@example.com"}'
+#[utoipa::path(
+    post,
+    path = "/password-reset",
+    tag = "account",
+    request_body = ResetRequest,
+    responses(
+        (status = 202, description = "A token is mailed if the email belongs to a user", body = FlashData),
+        (status = 429, description = "Too many attempts, retry after `Retry-After` seconds", body = ErrorBody),
+    )
+)]
+pub async fn request_password_reset(
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Extension(mailer): Extension<DynMailer>,
+    Extension(limiter): Extension<Arc<RateLimiter>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(input): Json<ResetRequest>,
+) -> Result<(StatusCode, Json<FlashData>), AccountError> {
+    let ip = rate_limit::client_ip(connect_info.as_ref());
+    limiter.check_reset(&ip, &input.email).await?;
+
+    let user = User::find()
+        .filter(user::Column::Email.eq(input.email.as_str()))
+        .one(conn)
+        .await?;
+    if let Some(user) = user {
+        // In the background, as waiting for the token and the email, or
+        // failing on them, would tell that the account exists
+        let conn = conn.clone();
+        tokio::spawn(async move {
+            let token = match issue(&conn, &user, Purpose::PasswordReset, RESET_LIFETIME).await {
+                Ok(token) => token,
+                Err(e) => {
+                    tracing::error!(user_id = user.id, "could not issue reset token: {:?}", e);
+                    return;
+                }
+            };
+            let email = Email {
+                to: user.email.clone(),
+                subject: "Reset your secret".to_owned(),
+                body: format!(
+                    "Someone asked to reset the secret of your account. If it was you, \
+                     send this token and a new secret to POST /password-reset/confirm \
+                     within an hour:\n\n{}\n\nOtherwise you can ignore this email.",
+                    token
+                ),
+            };
+            if let Err(e) = mailer.send(email).await {
+                tracing::error!(user_id = user.id, "could not send reset email: {:#}", e);
+            }
+        });
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(FlashData::success(
+            "If this email belongs to an account, a reset token is on its way",
+        )),
+    ))
+}
+
+// curl -H 'Content-Type: application/json' http://localhost:8000/password-reset/confirm --data '{"token":"...","secret":"new secret"}'
+#[utoipa::path(
+    post,
+    path = "/password-reset/confirm",
+    tag = "account",
+    request_body = ResetConfirmation,
+    responses(
+        (status = 200, description = "Secret changed", body = FlashData),
+        (status = 400, description = "Unknown, used or expired token, or empty secret", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    )
+)]
+pub async fn confirm_password_reset(
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Json(input): Json<ResetConfirmation>,
+) -> Result<Json<FlashData>, AccountError> {
+    if input.secret.is_empty() {
+        return Err(AccountError::EmptySecret);
+    }
+    let txn = conn.begin().await?;
+    let user = redeem(&txn, &input.token, Purpose::PasswordReset).await?;
+
+    let user_id = user.id;
+    let mut model: user::ActiveModel = user.clone().into();
+    model.hash = Set(hash_secret(&input.secret));
+    let updated = model.update(&txn).await?;
+    audit::updated(&txn, Some(user.email.as_str()), &user, &updated).await?;
+    RefreshToken::delete_many()
+        .filter(refresh_token::Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await?;
+    ApiKey::delete_many()
+        .filter(api_key::Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await?;
+    txn.commit().await?;
+    tracing::info!(user_id, "secret reset");
+
+    Ok(Json(FlashData::success("Secret successfully reset")))
+}
+
+// curl -X POST -H 'Authorization: Bearer ...' http://localhost:8000/email-verification
+#[utoipa::path(
+    post,
+    path = "/email-verification",
+    tag = "account",
+    responses(
+        (status = 202, description = "A token is mailed to the user", body = FlashData),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 500, description = "Database error or email not sent", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn request_email_verification(
+    claims: Claims,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Extension(mailer): Extension<DynMailer>,
+) -> Result<(StatusCode, Json<FlashData>), AccountError> {
+    let user = User::find()
+        .filter(user::Column::Email.eq(claims.sub()))
+        .one(conn)
+        .await?
+        .ok_or(AccountError::Auth(AuthError::InvalidToken))?;
+    if user.email_verified_at.is_some() {
+        return Ok((
+            StatusCode::OK,
+            Json(FlashData::success("Email already verified")),
+        ));
+    }
+
+    let token = issue(
+        conn,
+        &user,
+        Purpose::EmailVerification,
+        VERIFICATION_LIFETIME,
+    )
+    .await?;
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Verify your email".to_owned(),
+        body: format!(
+            "To confirm that this address is yours, send this token to \
+             POST /email-verification/confirm within a day:\n\n{}\n",
+            token
+        ),
+    };
+    mailer.send(email).await.map_err(|e| {
+        tracing::error!(
+            user_id = user.id,
+            "could not send verification email: {:#}",
+            e
+        );
+        AccountError::Mail
+    })?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(FlashData::success("A verification token is on its way")),
+    ))
+}
+
+// curl -H 'Content-Type: application/json' http://localhost:8000/email-verification/confirm --data '{"token":"..."}'
+#[utoipa::path(
+    post,
+    path = "/email-verification/confirm",
+    tag = "account",
+    request_body = VerificationConfirmation,
+    responses(
+        (status = 200, description = "Email verified", body = FlashData),
+        (status = 400, description = "Unknown, used or expired token", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    )
+)]
+pub async fn confirm_email_verification(
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Json(input): Json<VerificationConfirmation>,
+) -> Result<Json<FlashData>, AccountError> {
+    let user = redeem(conn, &input.token, Purpose::EmailVerification).await?;
+
+    let user_id = user.id;
+    let mut model: user::ActiveModel = user.clone().into();
+    model.email_verified_at = Set(Some(Utc::now()));
+    let updated = model.update(conn).await?;
+    audit::updated(conn, Some(user.email.as_str()), &user, &updated).await?;
+    tracing::info!(user_id, "email verified");
+
+    Ok(Json(FlashData::success("Email successfully verified")))
+}
+
+/// Store a new token for `purpose`, replacing any previous one, and return it.
+pub(crate) async fn issue(
+    conn: &DatabaseConnection,
+    user: &user::Model,
+    purpose: Purpose,
+    lifetime: Duration,
+) -> Result<String, AccountError> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| AccountError::TokenCreation)?;
+    let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    let lifetime = chrono::Duration::from_std(lifetime).map_err(|_| AccountError::TokenCreation)?;
+
+    UserToken::delete_many()
+        .filter(user_token::Column::UserId.eq(user.id))
+        .filter(user_token::Column::Purpose.eq(purpose.as_str()))
+        .exec(conn)
+        .await?;
+    user_token::ActiveModel {
+        user_id: Set(user.id),
+        purpose: Set(purpose.as_str().to_owned()),
+        hash: Set(hash_secret(&token)),
+        expires_at: Set(Utc::now() + lifetime),
+        ..Default::default()
+    }
+    .insert(conn)
+    .await?;
+
+    Ok(token)
+}
+
+/// Use up `token` and return its user, if it was issued for `purpose` and
+/// has not expired.
+pub(crate) async fn redeem<C: ConnectionTrait>(
+    conn: &C,
+    token: &str,
+    purpose: Purpose,
+) -> Result<user::Model, AccountError> {
+    let (stored, user) = UserToken::find()
+        .filter(user_token::Column::Hash.eq(hash_secret(token)))
+        .filter(user_token::Column::Purpose.eq(purpose.as_str()))
+        .find_also_related(User)
+        .one(conn)
+        .await?
+        .ok_or(AccountError::InvalidToken)?;
+    // Of two requests presenting the same token, only the one that deletes it
+    // goes on
+    let deleted = UserToken::delete_by_id(stored.id).exec(conn).await?;
+    if deleted.rows_affected == 0 || stored.expires_at <= Utc::now() {
+        return Err(AccountError::InvalidToken);
+    }
+
+    user.ok_or(AccountError::InvalidToken)
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    /// Unknown, used, expired or mistyped token
+    InvalidToken,
+    EmptySecret,
+    TokenCreation,
+    Mail,
+    Auth(AuthError),
+    Database(DbErr),
+}
+
+impl From<DbErr> for AccountError {
+    fn from(err: DbErr) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl From<AuthError> for AccountError {
+    fn from(err: AuthError) -> Self {
+        Self::Auth(err)
+    }
+}
+
+impl IntoResponse for AccountError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            AccountError::InvalidToken => (StatusCode::BAD_REQUEST, "Invalid or expired token"),
+            AccountError::EmptySecret => (StatusCode::BAD_REQUEST, "Secret must not be empty"),
+            AccountError::TokenCreation => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Token creation error")
+            }
+            AccountError::Mail => (StatusCode::INTERNAL_SERVER_ERROR, "Could not send email"),
+            AccountError::Auth(err) => return err.into_response(),
+            AccountError::Database(err) => {
+                tracing::error!("database error: {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+            }
+        };
+        let body = Json(json!({
+            "error": error_message,
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_app::{TestApp, EMAIL, SECRET};
+
+    /// Token in the last email sent to `to`, on its own paragraph.
+    fn mailed_token(app: &TestApp, to: &str) -> String {
+        let email = app
+            .mailer
+            .sent()
+            .into_iter()
+            .rev()
+            .find(|email| email.to == to)
+            .expect("no email sent");
+        email.body.split("\n\n").nth(1).unwrap().trim().to_owned()
+    }
+
+    #[tokio::test]
+    async fn password_reset() {
+        let app = TestApp::new().await;
+
+        // Same answer for unknown emails, but nothing is sent
+        for email in [EMAIL, "nobody@example.com"] {
+            app.post("/password-reset")
+                .json(json!({ "email": email }))
+                .send()
+                .await
+                .assert_status(StatusCode::ACCEPTED);
+        }
+        assert_eq!(app.emails(1).await.len(), 1);
+        let token = mailed_token(&app, EMAIL);
+        let key = app.api_key(&app.token().await).await;
+
+        app.post("/password-reset/confirm")
+            .json(json!({"token": token, "secret": ""}))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Secret must not be empty");
+        let response = app
+            .post("/password-reset/confirm")
+            .json(json!({"token": token, "secret": "new secret"}))
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(
+            response.json(),
+            json!({"kind": "success", "message": "Secret successfully reset"})
+        );
+        app.authorize(EMAIL, SECRET)
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Wrong credentials");
+        app.authorize(EMAIL, "new secret")
+            .await
+            .assert_status(StatusCode::OK);
+        app.get("/api/")
+            .bearer(&key)
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+
+        // Tokens work once
+        app.post("/password-reset/confirm")
+            .json(json!({"token": token, "secret": "again"}))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Invalid or expired token");
+
+        // and expire
+        app.post("/password-reset")
+            .json(json!({ "email": EMAIL }))
+            .send()
+            .await
+            .assert_status(StatusCode::ACCEPTED);
+        app.emails(2).await;
+        let token = mailed_token(&app, EMAIL);
+        entity::user_token::Entity::update_many()
+            .col_expr(
+                entity::user_token::Column::ExpiresAt,
+                sea_orm::sea_query::Expr::value(chrono::Utc::now() - chrono::Duration::hours(1)),
+            )
+            .exec(&app.conn)
+            .await
+            .unwrap();
+        app.post("/password-reset/confirm")
+            .json(json!({"token": token, "secret": "again"}))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Invalid or expired token");
+    }
+
+    #[tokio::test]
+    async fn email_verification() {
+        let app = TestApp::new().await;
+        let token = app.token().await;
+        let verified = || async {
+            entity::user::Entity::find_by_id(1)
+                .one(&app.conn)
+                .await
+                .unwrap()
+                .unwrap()
+                .email_verified_at
+                .is_some()
+        };
+
+        app.post("/email-verification")
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+        app.post("/email-verification")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::ACCEPTED);
+        let mailed = mailed_token(&app, EMAIL);
+        assert!(!verified().await);
+
+        // A reset token is no verification token
+        app.post("/email-verification/confirm")
+            .json(json!({ "token": "unknown" }))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Invalid or expired token");
+        app.post("/password-reset/confirm")
+            .json(json!({"token": mailed, "secret": "new secret"}))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Invalid or expired token");
+
+        app.post("/email-verification/confirm")
+            .json(json!({ "token": mailed }))
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(verified().await);
+
+        let response = app
+            .post("/email-verification")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(response.json()["message"], "Email already verified");
+    }
+}
@@ -0,0 +1,420 @@
+//! Personal API keys, for scripts and CI jobs that should not hold a user's
+//! secret.
+//!
+//! Users manage their keys at `/api-keys`. A key is shown once, when it is
+//! created, and only its hash is stored in `api_key`. Keys are sent like
+//! access tokens (`Authorization: Bearer pk_...`) and give the `Claims` of
+//! their owner with the key's scopes, which can only be a subset of the
+//! scopes of the token that created it. A key lives at most as long as that
+//! token, and keys cannot manage keys: a leaked key must not mint more.
+
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use entity::api_key::{self, Entity as ApiKey};
+use entity::user::{self, Entity as User};
+use ring::rand::{SecureRandom, SystemRandom};
+use sea_orm::{prelude::*, QueryOrder, Set};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::post_service::{hash_secret, AuthError, Claims};
+use crate::scope;
+
+/// Tells keys apart from JWTs, which always start with `ey`.
+pub const PREFIX: &str = "pk_";
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewApiKey {
+    /// What the key is for, e.g. `ci`
+    name: String,
+    /// Space-separated scopes, defaults to those of the calling token
+    scopes: Option<String>,
+    /// When the key stops working, at the latest when the calling token
+    /// expires
+    #[schema(value_type = Option<String>, format = DateTime)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiKeyInfo {
+    id: i32,
+    name: String,
+    scopes: String,
+    #[schema(value_type = String, format = DateTime)]
+    created_at: DateTime<Utc>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// A new key, the only time it is shown
+#[derive(Serialize, ToSchema)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    info: ApiKeyInfo,
+    key: String,
+}
+
+impl From<api_key::Model> for ApiKeyInfo {
+    fn from(model: api_key::Model) -> Self {
+        Self {
+            id: model.id,
+            name: model.name,
+            scopes: model.scopes,
+            created_at: model.created_at,
+            expires_at: model.expires_at,
+        }
+    }
+}
+
+// curl -H 'Authorization: Bearer ...' -H 'Content-Type: application/json' http://localhost:8000/api-keys --data '{"name":"ci","scopes":"posts:read"}'
+#[utoipa::path(
+    post,
+    path = "/api-keys",
+    tag = "api-keys",
+    request_body = NewApiKey,
+    responses(
+        (status = 201, description = "Key created; `key` is not shown again", body = CreatedApiKey),
+        (status = 400, description = "Empty name, scope not held by the token or expiry in the past", body = ErrorBody),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Authenticated with an API key", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn create_api_key(
+    claims: Claims,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Json(input): Json<NewApiKey>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), ApiKeyError> {
+    require_token(&claims)?;
+    if input.name.trim().is_empty() {
+        return Err(ApiKeyError::Invalid("Name must not be empty"));
+    }
+    let scopes = input.scopes.unwrap_or_else(|| claims.scope().to_owned());
+    if !scope::is_subset(&scopes, claims.scope()) {
+        return Err(ApiKeyError::Invalid("Scopes must be held by the token"));
+    }
+    let now = Utc::now();
+    if input.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(ApiKeyError::Invalid("Expiry must be in the future"));
+    }
+    let expires_at = match (input.expires_at, claims.expires_at()) {
+        (Some(requested), Some(token)) => Some(requested.min(token)),
+        (requested, token) => requested.or(token),
+    };
+    let user = current_user(conn, &claims).await?;
+
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| ApiKeyError::KeyCreation)?;
+    let key = format!(
+        "{}{}",
+        PREFIX,
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    );
+    let model = api_key::ActiveModel {
+        user_id: Set(user.id),
+        name: Set(input.name),
+        hash: Set(hash_secret(&key)),
+        scopes: Set(scopes),
+        created_at: Set(now),
+        expires_at: Set(expires_at),
+        ..Default::default()
+    }
+    .insert(conn)
+    .await?;
+    tracing::info!(user_id = user.id, key_id = model.id, "created api key");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedApiKey {
+            info: model.into(),
+            key,
+        }),
+    ))
+}
+
+// curl -H 'Authorization: Bearer ...' http://localhost:8000/api-keys
+#[utoipa::path(
+    get,
+    path = "/api-keys",
+    tag = "api-keys",
+    responses(
+        (status = 200, description = "Keys of the user, oldest first", body = [ApiKeyInfo]),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Authenticated with an API key", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_api_keys(
+    claims: Claims,
+    Extension(ref conn): Extension<DatabaseConnection>,
+) -> Result<Json<Vec<ApiKeyInfo>>, ApiKeyError> {
+    require_token(&claims)?;
+    let user = current_user(conn, &claims).await?;
+    let keys = ApiKey::find()
+        .filter(api_key::Column::UserId.eq(user.id))
+        .order_by_asc(api_key::Column::Id)
+        .all(conn)
+        .await?;
+
+    Ok(Json(keys.into_iter().map(ApiKeyInfo::from).collect()))
+}
+
+// curl -X DELETE -H 'Authorization: Bearer ...' http://localhost:8000/api-keys/1
+#[utoipa::path(
+    delete,
+    path = "/api-keys/{id}",
+    tag = "api-keys",
+    params(("id" = i32, Path, description = "Key id")),
+    responses(
+        (status = 204, description = "Key revoked"),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Authenticated with an API key", body = ErrorBody),
+        (status = 404, description = "No key with this id for the user", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn revoke_api_key(
+    claims: Claims,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, ApiKeyError> {
+    require_token(&claims)?;
+    let user = current_user(conn, &claims).await?;
+    let result = ApiKey::delete_many()
+        .filter(api_key::Column::Id.eq(id))
+        .filter(api_key::Column::UserId.eq(user.id))
+        .exec(conn)
+        .await?;
+    if result.rows_affected == 0 {
+        return Err(ApiKeyError::NotFound);
+    }
+    tracing::info!(user_id = user.id, key_id = id, "revoked api key");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `Claims` of the owner of `key`, with the key's scopes.
+pub async fn authenticate(conn: &DatabaseConnection, key: &str) -> Result<Claims, AuthError> {
+    let found = ApiKey::find()
+        .filter(api_key::Column::Hash.eq(hash_secret(key)))
+        .find_also_related(User)
+        .one(conn)
+        .await
+        .map_err(|e| {
+            tracing::error!("could not find api key: {}", e);
+            AuthError::Database
+        })?;
+    let (key, user) = match found {
+        Some((key, Some(user))) => (key, user),
+        _ => return Err(AuthError::InvalidToken),
+    };
+    if key
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Err(AuthError::ExpiredToken);
+    }
+    // Keys without expiry are checked against the database on every use, so
+    // the claim is never looked at
+    let exp = key
+        .expires_at
+        .map_or(usize::MAX, |expires_at| expires_at.timestamp() as usize);
+
+    Ok(Claims::for_api_key(&user, &key.scopes, exp))
+}
+
+fn require_token(claims: &Claims) -> Result<(), ApiKeyError> {
+    if claims.is_api_key() {
+        return Err(ApiKeyError::KeyAuthenticated);
+    }
+
+    Ok(())
+}
+
+async fn current_user(
+    conn: &DatabaseConnection,
+    claims: &Claims,
+) -> Result<user::Model, ApiKeyError> {
+    User::find()
+        .filter(user::Column::Email.eq(claims.sub()))
+        .one(conn)
+        .await?
+        .ok_or(ApiKeyError::UnknownUser)
+}
+
+#[derive(Debug)]
+pub enum ApiKeyError {
+    /// Rejected request body
+    Invalid(&'static str),
+    NotFound,
+    /// Keys cannot be managed with a key
+    KeyAuthenticated,
+    /// The token outlived its user
+    UnknownUser,
+    KeyCreation,
+    Database(DbErr),
+}
+
+impl From<DbErr> for ApiKeyError {
+    fn from(err: DbErr) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl IntoResponse for ApiKeyError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            ApiKeyError::Invalid(message) => (StatusCode::BAD_REQUEST, message),
+            ApiKeyError::NotFound => (StatusCode::NOT_FOUND, "API key not found"),
+            ApiKeyError::KeyAuthenticated => {
+                (StatusCode::FORBIDDEN, "API keys cannot manage API keys")
+            }
+            ApiKeyError::UnknownUser => return AuthError::InvalidToken.into_response(),
+            ApiKeyError::KeyCreation => (StatusCode::INTERNAL_SERVER_ERROR, "Key creation error"),
+            ApiKeyError::Database(err) => {
+                tracing::error!("database error: {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+            }
+        };
+        let body = Json(json!({
+            "error": error_message,
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_app::TestApp;
+
+    #[tokio::test]
+    async fn api_keys() {
+        let app = TestApp::new().await;
+        let token = app.token().await;
+
+        let response = app
+            .post("/api-keys")
+            .bearer(&token)
+            .json(json!({"name": "ci", "scopes": "posts:read"}))
+            .send()
+            .await
+            .assert_status(StatusCode::CREATED);
+        let body = response.json();
+        assert_eq!(
+            (body["id"].clone(), body["name"].clone()),
+            (json!(1), json!("ci"))
+        );
+        // Keys do not outlive the token that created them
+        let token_expiry = body["expires_at"].as_str().unwrap().to_owned();
+        let key = body["key"].as_str().unwrap().to_owned();
+        assert!(key.starts_with("pk_"));
+
+        let response = app
+            .get("/api-keys")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        let keys = response.json();
+        assert_eq!(keys.as_array().unwrap().len(), 1);
+        assert_eq!(keys[0]["expires_at"], token_expiry.as_str());
+        assert_eq!(keys[0]["scopes"], "posts:read");
+        assert!(keys[0].get("key").is_none());
+
+        // The key works like a token with its own scopes
+        app.get("/api/")
+            .bearer(&key)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        app.post("/api/")
+            .bearer(&key)
+            .json(json!({"title": "title11", "text": "text11", "new_col": 17}))
+            .send()
+            .await
+            .assert_error(StatusCode::FORBIDDEN, "Insufficient scope");
+        // and cannot manage keys
+        app.post("/api-keys")
+            .bearer(&key)
+            .json(json!({"name": "escalate", "scopes": "posts:read"}))
+            .send()
+            .await
+            .assert_error(StatusCode::FORBIDDEN, "API keys cannot manage API keys");
+        app.delete("/api-keys/1")
+            .bearer(&key)
+            .send()
+            .await
+            .assert_error(StatusCode::FORBIDDEN, "API keys cannot manage API keys");
+        app.post("/api-keys")
+            .bearer(&token)
+            .json(json!({"name": "escalate", "scopes": "users:admin"}))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Scopes must be held by the token");
+        let response = app
+            .post("/api-keys")
+            .bearer(&token)
+            .json(json!({"name": "forever", "expires_at": "2100-01-01T00:00:00Z"}))
+            .send()
+            .await
+            .assert_status(StatusCode::CREATED);
+        assert_eq!(response.json()["expires_at"], token_expiry.as_str());
+        app.post("/api-keys")
+            .bearer(&token)
+            .json(json!({"name": "old", "expires_at": "2020-01-01T00:00:00Z"}))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Expiry must be in the future");
+
+        // Expired keys are refused
+        let mut expired: entity::api_key::ActiveModel = entity::api_key::Entity::find_by_id(1)
+            .one(&app.conn)
+            .await
+            .unwrap()
+            .unwrap()
+            .into();
+        expired.expires_at = sea_orm::Set(Some("2020-01-01T00:00:00Z".parse().unwrap()));
+        expired.update(&app.conn).await.unwrap();
+        app.get("/api/")
+            .bearer(&key)
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Expired token");
+
+        // Revoked keys are unknown
+        app.delete("/api-keys/1")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        app.get("/api/")
+            .bearer(&key)
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+        app.delete("/api-keys/1")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "API key not found");
+        app.get("/api-keys")
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+    }
+}
@@ -0,0 +1,407 @@
+//! Audit trail of every create, update and delete of posts, users, comments
+//! and cakes.
+//!
+//! Each change adds an `audit_log` row with the email of the user who made it
+//! (`None` from the command line), the action, the record before and after as
+//! JSON, and the time. Post and comment changes are recorded in the
+//! transaction of the change itself. User snapshots leave out the secret hash
+//! and TOTP state, and logging in is not a change: only the last accepted TOTP
+//! step moves.
+//!
+//! Tokens with the `users:admin` scope read the entries of their tenant, and
+//! those of cakes, which belong to no tenant, at `GET /audit-log`.
+
+use axum::{
+    extract::{Extension, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use entity::audit_log::{self, Entity as AuditLog};
+use entity::{cake, posts, user};
+use sea_orm::{prelude::*, Condition, ConnectionTrait, QueryOrder, Set};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::scope::{RequireScope, UsersAdmin};
+
+const MAX_ENTRIES_PER_PAGE: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+/// Records whose changes are audited.
+pub trait Audited: Serialize {
+    /// Value of `audit_log.entity_type`, the name of the table
+    const ENTITY_TYPE: &'static str;
+
+    fn id(&self) -> i32;
+
+    fn tenant(&self) -> Option<i32>;
+
+    /// The record as stored in `before` and `after`
+    fn snapshot(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+impl Audited for posts::Model {
+    const ENTITY_TYPE: &'static str = "posts";
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn tenant(&self) -> Option<i32> {
+        Some(self.tenant_id)
+    }
+}
+
+impl Audited for user::Model {
+    const ENTITY_TYPE: &'static str = "user";
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn tenant(&self) -> Option<i32> {
+        Some(self.tenant_id)
+    }
+
+    fn snapshot(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or(Value::Null);
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("hash");
+            fields.remove("totp_last_step");
+        }
+        value
+    }
+}
+
+impl Audited for cake::Model {
+    const ENTITY_TYPE: &'static str = "cake";
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn tenant(&self) -> Option<i32> {
+        None
+    }
+}
+
+pub async fn created<C, M>(conn: &C, actor: Option<&str>, after: &M) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+    M: Audited,
+{
+    record(conn, actor, Action::Create, after, None, Some(after)).await
+}
+
+pub async fn updated<C, M>(
+    conn: &C,
+    actor: Option<&str>,
+    before: &M,
+    after: &M,
+) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+    M: Audited,
+{
+    record(
+        conn,
+        actor,
+        Action::Update,
+        after,
+        Some(before),
+        Some(after),
+    )
+    .await
+}
+
+pub async fn deleted<C, M>(conn: &C, actor: Option<&str>, before: &M) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+    M: Audited,
+{
+    record(conn, actor, Action::Delete, before, Some(before), None).await
+}
+
+async fn record<C, M>(
+    conn: &C,
+    actor: Option<&str>,
+    action: Action,
+    model: &M,
+    before: Option<&M>,
+    after: Option<&M>,
+) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+    M: Audited,
+{
+    audit_log::ActiveModel {
+        actor: Set(actor.map(str::to_owned)),
+        tenant_id: Set(model.tenant()),
+        action: Set(action.as_str().to_owned()),
+        entity_type: Set(M::ENTITY_TYPE.to_owned()),
+        entity_id: Set(model.id()),
+        before: Set(before.map(Audited::snapshot)),
+        after: Set(after.map(Audited::snapshot)),
+        created_at: Set(Utc::now()),
+        ..Default::default()
+    }
+    .insert(conn)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Email of the user who made the changes
+    actor: Option<String>,
+    /// `posts`, `user`, `comment` or `cake`
+    entity_type: Option<String>,
+    entity_id: Option<i32>,
+    /// Only changes at or after this time, e.g. `2022-11-14T00:00:00Z`
+    #[param(value_type = Option<String>, format = DateTime)]
+    from: Option<DateTime<Utc>>,
+    /// Only changes before this time
+    #[param(value_type = Option<String>, format = DateTime)]
+    to: Option<DateTime<Utc>>,
+    /// 1-based page number, defaults to 1
+    page: Option<usize>,
+    /// Page size, defaults to 20, at most 100
+    entries_per_page: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PaginationAuditLog {
+    #[schema(value_type = Vec<audit_log::AuditEntry>)]
+    entries: Vec<audit_log::Model>,
+    page: usize,
+    entries_per_page: usize,
+    num_pages: usize,
+}
+
+// curl -H 'Authorization: Bearer ...' 'http://localhost:8000/audit-log?entity_type=posts&entity_id=1'
+#[utoipa::path(
+    get,
+    path = "/audit-log",
+    tag = "audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "One page of changes, newest first", body = PaginationAuditLog),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `users:admin` scope", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["users:admin"]))
+)]
+pub async fn list_audit_log(
+    RequireScope(claims, _): RequireScope<UsersAdmin>,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<PaginationAuditLog>, AuditError> {
+    // Wrapped in `all`: filters added after a leading `any` would join it
+    let mut select = AuditLog::find().filter(
+        Condition::all().add(
+            Condition::any()
+                .add(audit_log::Column::TenantId.eq(claims.tenant()))
+                .add(audit_log::Column::TenantId.is_null()),
+        ),
+    );
+    if let Some(actor) = query.actor {
+        select = select.filter(audit_log::Column::Actor.eq(actor));
+    }
+    if let Some(entity_type) = query.entity_type {
+        select = select.filter(audit_log::Column::EntityType.eq(entity_type));
+    }
+    if let Some(entity_id) = query.entity_id {
+        select = select.filter(audit_log::Column::EntityId.eq(entity_id));
+    }
+    if let Some(from) = query.from {
+        select = select.filter(audit_log::Column::CreatedAt.gte(from));
+    }
+    if let Some(to) = query.to {
+        select = select.filter(audit_log::Column::CreatedAt.lt(to));
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let entries_per_page = query
+        .entries_per_page
+        .unwrap_or(20)
+        .clamp(1, MAX_ENTRIES_PER_PAGE);
+    let paginator = select
+        .order_by_desc(audit_log::Column::Id)
+        .paginate(conn, entries_per_page);
+    let num_pages = paginator.num_pages().await?;
+    let entries = paginator.fetch_page(page - 1).await?;
+
+    Ok(Json(PaginationAuditLog {
+        entries,
+        page,
+        entries_per_page,
+        num_pages,
+    }))
+}
+
+/// Failed audit log query.
+#[derive(Debug)]
+pub struct AuditError(DbErr);
+
+impl From<DbErr> for AuditError {
+    fn from(err: DbErr) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for AuditError {
+    fn into_response(self) -> Response {
+        tracing::error!("database error: {}", self.0);
+        let body = Json(json!({
+            "error": "Database error",
+        }));
+        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_app::{TestApp, ADMIN_EMAIL, EMAIL, OTHER_TENANT_EMAIL};
+
+    #[test]
+    fn user_snapshots_leave_out_secrets() {
+        let user = user::Model {
+            id: 1,
+            email: "account@example.com".to_owned(),
+            hash: "hash".to_owned(),
+            scopes: "posts:read".to_owned(),
+            tenant_id: 1,
+            email_verified_at: None,
+            totp_secret: Some("JBSWY3DPEHPK3PXP".to_owned()),
+            totp_enabled_at: None,
+            totp_last_step: Some(1),
+        };
+        assert_eq!(
+            user.snapshot(),
+            json!({
+                "id": 1,
+                "email": "account@example.com",
+                "scopes": "posts:read",
+                "tenant_id": 1,
+                "email_verified_at": null,
+                "totp_enabled_at": null,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn audit_log() {
+        let app = TestApp::new().await;
+        let token = app.token().await;
+        let admin_token = app.token_for(ADMIN_EMAIL).await;
+        let post = json!({"title": "title", "text": "text", "new_col": 1});
+        let query = |query: &str| {
+            let request = app
+                .get(&format!("/audit-log?{}", query))
+                .bearer(&admin_token);
+            async move { request.send().await.assert_status(StatusCode::OK).json() }
+        };
+
+        app.post("/api/")
+            .bearer(&token)
+            .json(post.clone())
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        app.patch("/api/1")
+            .bearer(&token)
+            .json(json!({"title": "updated", "text": "text", "new_col": 1}))
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        app.delete("/api/1")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        // Out of sight of the admin of the first tenant
+        app.post("/api/")
+            .bearer(&app.token_for(OTHER_TENANT_EMAIL).await)
+            .json(post)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+
+        app.get("/audit-log")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_error(StatusCode::FORBIDDEN, "Insufficient scope");
+
+        let page = query("entity_type=posts").await;
+        assert_eq!(page["num_pages"], 1);
+        let entries = page["entries"].as_array().unwrap();
+        let actions: Vec<_> = entries.iter().map(|entry| &entry["action"]).collect();
+        assert_eq!(actions, ["delete", "update", "create"]);
+        assert_eq!(entries[0]["actor"], EMAIL);
+        assert_eq!(entries[0]["entity_id"], 1);
+        assert_eq!(entries[0]["before"]["title"], "updated");
+        assert_eq!(entries[0]["after"], Value::Null);
+        assert_eq!(entries[1]["before"]["title"], "title");
+        assert_eq!(entries[2]["before"], Value::Null);
+        assert!(entries[0]["created_at"].is_string());
+
+        let page = query("entity_type=posts&entity_id=1&entries_per_page=2&page=2").await;
+        assert_eq!(page["num_pages"], 2);
+        assert_eq!(page["entries"][0]["action"], "create");
+        assert_eq!(
+            query(&format!("actor={}", OTHER_TENANT_EMAIL)).await["entries"],
+            json!([])
+        );
+        let hour = |offset: i64| {
+            (chrono::Utc::now() + chrono::Duration::hours(offset))
+                .format("%Y-%m-%dT%H:%M:%SZ")
+                .to_string()
+        };
+        let page = query(&format!(
+            "entity_type=posts&from={}&to={}",
+            hour(-1),
+            hour(1)
+        ))
+        .await;
+        assert_eq!(page["entries"].as_array().unwrap().len(), 3);
+        let page = query(&format!("entity_type=posts&from={}", hour(1))).await;
+        assert_eq!(page["entries"], json!([]));
+
+        // Seeded users, without their secret
+        let page = query("entity_type=user").await;
+        let entries = page["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1]["actor"], Value::Null);
+        assert_eq!(entries[1]["after"]["email"], EMAIL);
+        assert!(entries[1]["after"].get("hash").is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use std::env;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+use clap::{Parser, Subcommand};
+use entity::tenant::Entity as Tenant;
+use entity::user::{self, Entity as User};
+use migration::{Migrator, MigratorTrait};
+use sea_orm::{prelude::*, Set};
+
+use crate::audit;
+use crate::oauth::TokenConfig;
+use crate::post_service::{hash_secret, issue_token};
+use crate::scope::{Scope, DEFAULT_USER_SCOPES};
+use crate::seeder;
+
+#[derive(Debug, Parser)]
+#[clap(version, about = "Axum with SeaORM example app")]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run pending migrations and start the HTTP server (default)
+    Serve,
+    /// Apply, rollback or inspect schema migrations
+    Migrate {
+        #[clap(subcommand)]
+        command: MigrateCommand,
+    },
+    /// Upsert users, posts and cakes from a JSON or YAML fixtures file
+    Seed {
+        /// Environment whose fixtures are loaded (defaults to $APP_ENV or development)
+        #[clap(short, long, value_parser)]
+        env: Option<String>,
+        /// Explicit fixtures file, overrides --env
+        #[clap(value_parser)]
+        file: Option<PathBuf>,
+    },
+    /// Create a user that can log in through /authorize, with the secret
+    /// from $USER_SECRET or the first line of stdin
+    CreateUser {
+        #[clap(value_parser)]
+        email: String,
+        /// Space-separated scopes the user may be granted
+        #[clap(long, value_parser, default_value = DEFAULT_USER_SCOPES)]
+        scopes: String,
+        /// Id of the tenant the user belongs to
+        #[clap(long, value_parser, default_value_t = seeder::DEFAULT_TENANT)]
+        tenant: i32,
+    },
+    /// Print an access token for an existing user
+    IssueToken {
+        #[clap(value_parser)]
+        email: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateCommand {
+    /// Apply pending migrations
+    Up {
+        /// Number of pending migrations to apply
+        #[clap(short, long, value_parser)]
+        num: Option<u32>,
+    },
+    /// Rollback applied migrations
+    Down {
+        /// Number of applied migrations to rollback
+        #[clap(short, long, value_parser, default_value = "1")]
+        num: u32,
+    },
+    /// Check the status of all migrations
+    Status,
+}
+
+pub async fn migrate(conn: &DatabaseConnection, command: MigrateCommand) -> anyhow::Result<()> {
+    match command {
+        MigrateCommand::Up { num } => Migrator::up(conn, num).await?,
+        MigrateCommand::Down { num } => Migrator::down(conn, Some(num)).await?,
+        MigrateCommand::Status => Migrator::status(conn).await?,
+    }
+
+    Ok(())
+}
+
+pub async fn seed(
+    conn: &DatabaseConnection,
+    environment: Option<String>,
+    file: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let path = match file {
+        Some(file) => file,
+        None => seeder::fixtures_path(&environment.unwrap_or_else(seeder::current_env))?,
+    };
+    let report = seeder::seed_from_file(conn, &path).await?;
+    println!(
+        "seeded {}: {} inserted, {} updated",
+        path.display(),
+        report.inserted,
+        report.updated
+    );
+
+    Ok(())
+}
+
+/// The secret for `create-user`, kept off the command line where shell
+/// history and `ps` would show it.
+fn read_secret() -> anyhow::Result<String> {
+    if let Ok(secret) = env::var("USER_SECRET") {
+        return Ok(secret);
+    }
+    eprint!("secret: ");
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+pub async fn create_user(
+    conn: &DatabaseConnection,
+    email: String,
+    scopes: String,
+    tenant: i32,
+) -> anyhow::Result<()> {
+    let secret = read_secret()?;
+    if email.is_empty() || secret.is_empty() {
+        bail!("email and secret must not be empty");
+    }
+    if let Some(unknown) = scopes
+        .split(' ')
+        .find(|value| Scope::parse(value).is_none())
+    {
+        bail!("unknown scope {:?}", unknown);
+    }
+    if Tenant::find_by_id(tenant).one(conn).await?.is_none() {
+        bail!("tenant {} not found", tenant);
+    }
+    if find_user(conn, &email).await?.is_some() {
+        bail!("user {} already exists", email);
+    }
+    let user = user::ActiveModel {
+        email: Set(email),
+        hash: Set(hash_secret(&secret)),
+        scopes: Set(scopes),
+        tenant_id: Set(tenant),
+        ..Default::default()
+    }
+    .insert(conn)
+    .await?;
+    audit::created(conn, None, &user).await?;
+    println!("created user {} ({})", user.id, user.email);
+
+    Ok(())
+}
+
+pub async fn issue_token_for(conn: &DatabaseConnection, email: String) -> anyhow::Result<()> {
+    let user = find_user(conn, &email)
+        .await?
+        .ok_or_else(|| anyhow!("user {} not found", email))?;
+    let token = issue_token(&user, &TokenConfig::from_env()).map_err(|e| anyhow!("{:?}", e))?;
+    println!("{}", token);
+
+    Ok(())
+}
+
+async fn find_user(conn: &DatabaseConnection, email: &str) -> Result<Option<user::Model>, DbErr> {
+    User::find()
+        .filter(user::Column::Email.eq(email))
+        .one(conn)
+        .await
+}
@@ -0,0 +1,498 @@
+//! Comments on posts.
+//!
+//! Anyone who can read a post reads its comments, oldest first, at
+//! `GET /api/:id/comments`, and anyone who can write posts comments on it with
+//! `POST`. Only the author edits or deletes a comment, at `/comments/:id`.
+//! Comments are deleted with their post or their author. Adding, editing and
+//! deleting comments is audited, under the tenant of the post.
+
+use axum::{
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use entity::comment::{self, Entity as Comment};
+use entity::posts::{self, Entity as Posts};
+use entity::user::{self, Entity as User};
+use sea_orm::{prelude::*, ConnectionTrait, QueryOrder, Set, TransactionTrait};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::audit::{self, Audited};
+use crate::post_service::{AuthError, Claims};
+use crate::scope::{PostsRead, PostsWrite, RequireScope};
+
+const MAX_BODY_LEN: usize = 10_000;
+const MAX_COMMENTS_PER_PAGE: usize = 100;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CommentParams {
+    /// 1-based page number, defaults to 1
+    page: Option<usize>,
+    /// Page size, defaults to 20, at most 100
+    comments_per_page: Option<usize>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CommentInput {
+    /// Up to 10000 characters
+    body: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PaginationComment {
+    #[schema(value_type = Vec<comment::Comment>)]
+    comments: Vec<comment::Model>,
+    page: usize,
+    comments_per_page: usize,
+    num_pages: usize,
+}
+
+// curl -H 'Authorization: Bearer ...' http://localhost:8000/api/1/comments
+#[utoipa::path(
+    get,
+    path = "/api/{id}/comments",
+    tag = "comments",
+    params(("id" = i32, Path, description = "Post id"), CommentParams),
+    responses(
+        (status = 200, description = "One page of the comments of the post, oldest first", body = PaginationComment),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:read` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:read"]))
+)]
+pub async fn list_comments(
+    RequireScope(claims, _): RequireScope<PostsRead>,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Path(id): Path<i32>,
+    Query(params): Query<CommentParams>,
+) -> Result<Json<PaginationComment>, CommentError> {
+    find_post(conn, &claims, id).await?;
+
+    let page = params.page.unwrap_or(1).max(1);
+    let comments_per_page = params
+        .comments_per_page
+        .unwrap_or(20)
+        .clamp(1, MAX_COMMENTS_PER_PAGE);
+    let paginator = Comment::find()
+        .filter(comment::Column::PostId.eq(id))
+        .order_by_asc(comment::Column::Id)
+        .paginate(conn, comments_per_page);
+    let num_pages = paginator.num_pages().await?;
+    let comments = paginator.fetch_page(page - 1).await?;
+
+    Ok(Json(PaginationComment {
+        comments,
+        page,
+        comments_per_page,
+        num_pages,
+    }))
+}
+
+// curl -H 'Authorization: Bearer ...' -H 'Content-Type: application/json' http://localhost:8000/api/1/comments --data '{"body":"Nice post"}'
+#[utoipa::path(
+    post,
+    path = "/api/{id}/comments",
+    tag = "comments",
+    params(("id" = i32, Path, description = "Post id")),
+    request_body = CommentInput,
+    responses(
+        (status = 201, description = "Comment added", body = comment::Comment),
+        (status = 400, description = "Empty or too long body", body = ErrorBody),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:write` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:write"]))
+)]
+pub async fn create_comment(
+    RequireScope(claims, _): RequireScope<PostsWrite>,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Path(id): Path<i32>,
+    Json(input): Json<CommentInput>,
+) -> Result<(StatusCode, Json<comment::Model>), CommentError> {
+    let body = validate(input.body)?;
+    let txn = conn.begin().await?;
+    find_post(&txn, &claims, id).await?;
+    let author = current_user(&txn, &claims).await?;
+
+    let now = Utc::now();
+    let comment = comment::ActiveModel {
+        post_id: Set(id),
+        author_id: Set(author.id),
+        body: Set(body),
+        created_at: Set(now),
+        updated_at: Set(now),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    audit::created(&txn, Some(claims.sub()), &audited(&claims, &comment)).await?;
+    txn.commit().await?;
+    tracing::info!(post_id = id, comment_id = comment.id, "added comment");
+
+    Ok((StatusCode::CREATED, Json(comment)))
+}
+
+// curl -X PATCH -H 'Authorization: Bearer ...' -H 'Content-Type: application/json' http://localhost:8000/comments/1 --data '{"body":"Nice post!"}'
+#[utoipa::path(
+    patch,
+    path = "/comments/{id}",
+    tag = "comments",
+    params(("id" = i32, Path, description = "Comment id")),
+    request_body = CommentInput,
+    responses(
+        (status = 200, description = "Comment changed", body = comment::Comment),
+        (status = 400, description = "Empty or too long body", body = ErrorBody),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:write` scope or the user is not the author", body = ErrorBody),
+        (status = 404, description = "No comment with this id", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:write"]))
+)]
+pub async fn update_comment(
+    RequireScope(claims, _): RequireScope<PostsWrite>,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Path(id): Path<i32>,
+    Json(input): Json<CommentInput>,
+) -> Result<Json<comment::Model>, CommentError> {
+    let body = validate(input.body)?;
+    let txn = conn.begin().await?;
+    let before = own_comment(&txn, &claims, id).await?;
+
+    let mut model: comment::ActiveModel = before.clone().into();
+    model.body = Set(body);
+    model.updated_at = Set(Utc::now());
+    let comment = model.update(&txn).await?;
+    audit::updated(
+        &txn,
+        Some(claims.sub()),
+        &audited(&claims, &before),
+        &audited(&claims, &comment),
+    )
+    .await?;
+    txn.commit().await?;
+
+    Ok(Json(comment))
+}
+
+// curl -X DELETE -H 'Authorization: Bearer ...' http://localhost:8000/comments/1
+#[utoipa::path(
+    delete,
+    path = "/comments/{id}",
+    tag = "comments",
+    params(("id" = i32, Path, description = "Comment id")),
+    responses(
+        (status = 204, description = "Comment deleted"),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:write` scope or the user is not the author", body = ErrorBody),
+        (status = 404, description = "No comment with this id", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:write"]))
+)]
+pub async fn delete_comment(
+    RequireScope(claims, _): RequireScope<PostsWrite>,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, CommentError> {
+    let txn = conn.begin().await?;
+    let comment = own_comment(&txn, &claims, id).await?;
+    Comment::delete_by_id(comment.id).exec(&txn).await?;
+    audit::deleted(&txn, Some(claims.sub()), &audited(&claims, &comment)).await?;
+    txn.commit().await?;
+    tracing::info!(comment_id = id, "deleted comment");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The trimmed `body`, if neither empty nor too long.
+fn validate(body: String) -> Result<String, CommentError> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err(CommentError::Invalid("Comment must not be empty"));
+    }
+    if body.chars().count() > MAX_BODY_LEN {
+        return Err(CommentError::Invalid("Comment is too long"));
+    }
+
+    Ok(body.to_owned())
+}
+
+/// The post `id` of the caller's tenant.
+async fn find_post<C: ConnectionTrait>(
+    conn: &C,
+    claims: &Claims,
+    id: i32,
+) -> Result<posts::Model, CommentError> {
+    Posts::find_by_id(id)
+        .filter(posts::Column::TenantId.eq(claims.tenant()))
+        .one(conn)
+        .await?
+        .ok_or(CommentError::PostNotFound)
+}
+
+async fn current_user<C: ConnectionTrait>(
+    conn: &C,
+    claims: &Claims,
+) -> Result<user::Model, CommentError> {
+    User::find()
+        .filter(user::Column::Email.eq(claims.sub()))
+        .one(conn)
+        .await?
+        .ok_or(CommentError::UnknownUser)
+}
+
+/// The comment `id` on a post of the caller's tenant, if the caller wrote it.
+async fn own_comment<C: ConnectionTrait>(
+    conn: &C,
+    claims: &Claims,
+    id: i32,
+) -> Result<comment::Model, CommentError> {
+    let comment = match Comment::find_by_id(id)
+        .find_also_related(Posts)
+        .one(conn)
+        .await?
+    {
+        Some((comment, Some(post))) if post.tenant_id == claims.tenant() => comment,
+        _ => return Err(CommentError::CommentNotFound),
+    };
+    let user = current_user(conn, claims).await?;
+    if comment.author_id != user.id {
+        return Err(CommentError::NotAuthor);
+    }
+
+    Ok(comment)
+}
+
+/// A comment as audited: comments have no tenant of their own, they take the
+/// one of their post, which is the caller's.
+#[derive(Serialize)]
+struct AuditedComment<'a> {
+    #[serde(skip)]
+    tenant: i32,
+    #[serde(flatten)]
+    comment: &'a comment::Model,
+}
+
+fn audited<'a>(claims: &Claims, comment: &'a comment::Model) -> AuditedComment<'a> {
+    AuditedComment {
+        tenant: claims.tenant(),
+        comment,
+    }
+}
+
+impl Audited for AuditedComment<'_> {
+    const ENTITY_TYPE: &'static str = "comment";
+
+    fn id(&self) -> i32 {
+        self.comment.id
+    }
+
+    fn tenant(&self) -> Option<i32> {
+        Some(self.tenant)
+    }
+}
+
+#[derive(Debug)]
+pub enum CommentError {
+    /// Rejected request body
+    Invalid(&'static str),
+    PostNotFound,
+    CommentNotFound,
+    NotAuthor,
+    /// The token outlived its user
+    UnknownUser,
+    Database(DbErr),
+}
+
+impl From<DbErr> for CommentError {
+    fn from(err: DbErr) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl IntoResponse for CommentError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            CommentError::Invalid(message) => (StatusCode::BAD_REQUEST, message),
+            CommentError::PostNotFound => (StatusCode::NOT_FOUND, "Post not found"),
+            CommentError::CommentNotFound => (StatusCode::NOT_FOUND, "Comment not found"),
+            CommentError::NotAuthor => (
+                StatusCode::FORBIDDEN,
+                "Only the author can change a comment",
+            ),
+            CommentError::UnknownUser => return AuthError::InvalidToken.into_response(),
+            CommentError::Database(err) => {
+                tracing::error!("database error: {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+            }
+        };
+        let body = Json(json!({
+            "error": error_message,
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_app::{TestApp, ADMIN_EMAIL, EMAIL, OTHER_TENANT_EMAIL};
+
+    #[test]
+    fn validates_bodies() {
+        assert_eq!(validate("  Nice post\n".to_owned()).unwrap(), "Nice post");
+        assert!(matches!(
+            validate(" \n".to_owned()),
+            Err(CommentError::Invalid("Comment must not be empty"))
+        ));
+        assert!(validate("é".repeat(MAX_BODY_LEN)).is_ok());
+        assert!(matches!(
+            validate("a".repeat(MAX_BODY_LEN + 1)),
+            Err(CommentError::Invalid("Comment is too long"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn post_comments() {
+        use entity::comment::Entity as Comment;
+
+        let app = TestApp::new().await;
+        let token = app.token().await;
+        let admin_token = app.token_for(ADMIN_EMAIL).await;
+        let comment = |token: &str, body: &str| {
+            app.post("/api/1/comments")
+                .bearer(token)
+                .json(json!({ "body": body }))
+                .send()
+        };
+
+        app.post("/api/")
+            .bearer(&token)
+            .json(json!({"title": "title", "text": "text", "new_col": 1}))
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        let first = comment(&token, " first ")
+            .await
+            .assert_status(StatusCode::CREATED)
+            .json();
+        assert_eq!(first["body"], "first");
+        assert_eq!(first["post_id"], 1);
+        comment(&admin_token, "second")
+            .await
+            .assert_status(StatusCode::CREATED);
+        comment(&token, "third")
+            .await
+            .assert_status(StatusCode::CREATED);
+        comment(&token, "  ")
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Comment must not be empty");
+        app.post("/api/42/comments")
+            .bearer(&token)
+            .json(json!({"body": "lost"}))
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+
+        let page = app
+            .get("/api/1/comments?page=2&comments_per_page=2")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK)
+            .json();
+        assert_eq!(page["num_pages"], 2);
+        assert_eq!(page["comments_per_page"], 2);
+        assert_eq!(page["comments"][0]["body"], "third");
+
+        let uri = format!("/comments/{}", first["id"]);
+        let response = app
+            .patch(&uri)
+            .bearer(&token)
+            .json(json!({"body": "edited"}))
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(response.json()["body"], "edited");
+        // Only by the author
+        app.patch(&uri)
+            .bearer(&admin_token)
+            .json(json!({"body": "hijacked"}))
+            .send()
+            .await
+            .assert_error(
+                StatusCode::FORBIDDEN,
+                "Only the author can change a comment",
+            );
+        app.delete(&uri)
+            .bearer(&admin_token)
+            .send()
+            .await
+            .assert_error(
+                StatusCode::FORBIDDEN,
+                "Only the author can change a comment",
+            );
+        // Nor seen from another tenant
+        let other_token = app.token_for(OTHER_TENANT_EMAIL).await;
+        app.delete(&uri)
+            .bearer(&other_token)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Comment not found");
+        app.get("/api/1/comments")
+            .bearer(&other_token)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+
+        app.delete(&uri)
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        app.delete(&uri)
+            .bearer(&token)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Comment not found");
+
+        // Every change is audited under the tenant of the post
+        let page = app
+            .get(&format!(
+                "/audit-log?entity_type=comment&entity_id={}",
+                first["id"]
+            ))
+            .bearer(&admin_token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK)
+            .json();
+        let entries = page["entries"].as_array().unwrap();
+        let actions: Vec<_> = entries.iter().map(|entry| &entry["action"]).collect();
+        assert_eq!(actions, ["delete", "update", "create"]);
+        assert_eq!(entries[0]["actor"], EMAIL);
+        assert_eq!(entries[1]["before"]["body"], "first");
+        assert_eq!(entries[1]["after"]["body"], "edited");
+
+        // The rest go with the post
+        app.delete("/api/1")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(Comment::find().all(&app.conn).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+use sea_orm::{ConnectOptions, DatabaseConnection, DbErr};
+#[cfg(feature = "sqlx-mysql")]
+use sqlx::mysql::{MySql, MySqlConnectOptions, MySqlPool};
+#[cfg(feature = "sqlx-postgres")]
+use sqlx::postgres::{PgConnectOptions, PgPool, Postgres};
+#[cfg(feature = "sqlx-sqlite")]
+use sqlx::sqlite::{Sqlite, SqliteConnectOptions, SqlitePool};
+
+/// The sqlx pool behind a `DatabaseConnection`, one variant per enabled
+/// database feature.
+#[derive(Debug, Clone)]
+pub enum DbPool {
+    #[cfg(feature = "sqlx-postgres")]
+    Postgres(PgPool),
+    #[cfg(feature = "sqlx-mysql")]
+    MySql(MySqlPool),
+    #[cfg(feature = "sqlx-sqlite")]
+    Sqlite(SqlitePool),
+}
+
+impl DbPool {
+    /// Open connections, idle or in use
+    pub fn size(&self) -> u32 {
+        match self {
+            #[cfg(feature = "sqlx-postgres")]
+            Self::Postgres(pool) => pool.size(),
+            #[cfg(feature = "sqlx-mysql")]
+            Self::MySql(pool) => pool.size(),
+            #[cfg(feature = "sqlx-sqlite")]
+            Self::Sqlite(pool) => pool.size(),
+        }
+    }
+
+    pub fn num_idle(&self) -> usize {
+        match self {
+            #[cfg(feature = "sqlx-postgres")]
+            Self::Postgres(pool) => pool.num_idle(),
+            #[cfg(feature = "sqlx-mysql")]
+            Self::MySql(pool) => pool.num_idle(),
+            #[cfg(feature = "sqlx-sqlite")]
+            Self::Sqlite(pool) => pool.num_idle(),
+        }
+    }
+
+    pub async fn close(&self) {
+        match self {
+            #[cfg(feature = "sqlx-postgres")]
+            Self::Postgres(pool) => pool.close().await,
+            #[cfg(feature = "sqlx-mysql")]
+            Self::MySql(pool) => pool.close().await,
+            #[cfg(feature = "sqlx-sqlite")]
+            Self::Sqlite(pool) => pool.close().await,
+        }
+    }
+}
+
+/// Connect with `options`, keeping a handle on the underlying sqlx pool.
+///
+/// `Database::connect` hides the pool, so it is built here for the backend
+/// the URL names and handed to SeaORM; the returned `DbPool` shares its
+/// connections, feeds the pool metrics and is closed explicitly on shutdown.
+/// Pool sizing and statement logging follow `ConnectOptions` the same way
+/// SeaORM's own connectors apply them, including its single-connection
+/// default for SQLite.
+pub async fn connect(
+    options: impl Into<ConnectOptions>,
+) -> Result<(DatabaseConnection, DbPool), DbErr> {
+    let mut options = options.into();
+    let url = options.get_url().to_owned();
+    let conn_err = |e: sqlx::Error| DbErr::Conn(e.to_string());
+
+    #[cfg(feature = "sqlx-postgres")]
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        let connect_options = sqlx_options::<PgConnectOptions>(&options)?;
+        let pool = options
+            .pool_options::<Postgres>()
+            .connect_with(connect_options)
+            .await
+            .map_err(conn_err)?;
+        return Ok((
+            sea_orm::SqlxPostgresConnector::from_sqlx_postgres_pool(pool.clone()),
+            DbPool::Postgres(pool),
+        ));
+    }
+    #[cfg(feature = "sqlx-mysql")]
+    if url.starts_with("mysql://") {
+        let connect_options = sqlx_options::<MySqlConnectOptions>(&options)?;
+        let pool = options
+            .pool_options::<MySql>()
+            .connect_with(connect_options)
+            .await
+            .map_err(conn_err)?;
+        return Ok((
+            sea_orm::SqlxMySqlConnector::from_sqlx_mysql_pool(pool.clone()),
+            DbPool::MySql(pool),
+        ));
+    }
+    #[cfg(feature = "sqlx-sqlite")]
+    if url.starts_with("sqlite:") {
+        let connect_options = sqlx_options::<SqliteConnectOptions>(&options)?;
+        if options.get_max_connections().is_none() {
+            options.max_connections(1);
+        }
+        let pool = options
+            .pool_options::<Sqlite>()
+            .connect_with(connect_options)
+            .await
+            .map_err(conn_err)?;
+        return Ok((
+            sea_orm::SqlxSqliteConnector::from_sqlx_sqlite_pool(pool.clone()),
+            DbPool::Sqlite(pool),
+        ));
+    }
+
+    Err(DbErr::Conn(format!(
+        "no database feature enabled for the scheme of {}",
+        url.split(':').next().unwrap_or_default()
+    )))
+}
+
+/// Parse the URL into sqlx connect options with SeaORM's logging settings.
+fn sqlx_options<O>(options: &ConnectOptions) -> Result<O, DbErr>
+where
+    O: sqlx::ConnectOptions,
+{
+    let mut opt: O = options
+        .get_url()
+        .parse()
+        .map_err(|e: sqlx::Error| DbErr::Conn(e.to_string()))?;
+    if options.get_sqlx_logging() {
+        opt.log_statements(options.get_sqlx_logging_level());
+    } else {
+        opt.disable_statement_logging();
+    }
+    Ok(opt)
+}
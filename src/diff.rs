@@ -0,0 +1,118 @@
+//! Line-level diff of two texts, for comparing post revisions.
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Above this many pairs of lines the texts are reported as entirely
+/// replaced instead of computing the longest common subsequence, which takes
+/// memory proportional to the number of pairs.
+const MAX_PAIRS: usize = 4_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    Equal,
+    Insert,
+    Delete,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct DiffLine {
+    op: DiffOp,
+    line: String,
+}
+
+impl DiffLine {
+    fn new(op: DiffOp, line: &str) -> Self {
+        Self {
+            op,
+            line: line.to_owned(),
+        }
+    }
+}
+
+/// The lines of `old` and `new` in order, each kept, deleted or inserted,
+/// with as few changes as possible. Deleted lines come before the lines
+/// inserted in their place.
+pub fn lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    if old.len().saturating_mul(new.len()) > MAX_PAIRS {
+        return old
+            .iter()
+            .map(|line| DiffLine::new(DiffOp::Delete, line))
+            .chain(new.iter().map(|line| DiffLine::new(DiffOp::Insert, line)))
+            .collect();
+    }
+
+    // common[i][j]: length of the longest common subsequence of old[i..] and
+    // new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push(DiffLine::new(DiffOp::Equal, old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] > common[i + 1][j]) {
+            diff.push(DiffLine::new(DiffOp::Insert, new[j]));
+            j += 1;
+        } else {
+            diff.push(DiffLine::new(DiffOp::Delete, old[i]));
+            i += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(diff: &[DiffLine]) -> Vec<String> {
+        diff.iter()
+            .map(|line| {
+                let sign = match line.op {
+                    DiffOp::Equal => ' ',
+                    DiffOp::Insert => '+',
+                    DiffOp::Delete => '-',
+                };
+                format!("{}{}", sign, line.line)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diffs_lines() {
+        let old = "a\nb\nc\nd\ne";
+        let new = "a\nc\nx\nd\ne\nf";
+        assert_eq!(
+            render(&lines(old, new)),
+            [" a", "-b", " c", "+x", " d", " e", "+f"]
+        );
+    }
+
+    #[test]
+    fn replaced_lines_are_deleted_first() {
+        assert_eq!(
+            render(&lines("a\nb\nc", "a\nx\nc")),
+            [" a", "-b", "+x", " c"]
+        );
+        assert_eq!(render(&lines("", "x")), ["+x"]);
+        assert_eq!(render(&lines("x", "")), ["-x"]);
+        assert!(lines("same\ntext", "same\ntext")
+            .iter()
+            .all(|line| line.op == DiffOp::Equal));
+    }
+}
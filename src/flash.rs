@@ -0,0 +1,56 @@
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tower_cookies::{Cookie, Cookies};
+
+#[derive(Deserialize)]
+struct ValuedMessage<T> {
+    #[serde(rename = "_")]
+    value: T,
+}
+
+#[derive(Serialize)]
+struct ValuedMessageRef<'a, T> {
+    #[serde(rename = "_")]
+    value: &'a T,
+}
+
+const FLASH_COOKIE_NAME: &str = "_flash";
+
+/// Read the flash message set by the previous form post, and clear it so it
+/// is only shown once.
+pub fn get_flash_cookie<T>(cookies: &Cookies) -> Option<T>
+where
+    T: DeserializeOwned,
+{
+    let flash_cookie = cookies.get(FLASH_COOKIE_NAME)?;
+    cookies.remove(Cookie::named(FLASH_COOKIE_NAME));
+    serde_json::from_str::<ValuedMessage<T>>(flash_cookie.value())
+        .ok()
+        .map(|ValuedMessage { value }| value)
+}
+
+pub type PostResponse = (StatusCode, HeaderMap);
+
+/// Store `data` as flash message and redirect to `location` (post/redirect/get).
+pub fn post_response<T>(cookies: &Cookies, data: T, location: &str) -> PostResponse
+where
+    T: Serialize,
+{
+    let valued_message_ref = ValuedMessageRef { value: &data };
+
+    let mut cookie = Cookie::new(
+        FLASH_COOKIE_NAME,
+        serde_json::to_string(&valued_message_ref).unwrap(),
+    );
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookies.add(cookie);
+
+    let mut header = HeaderMap::new();
+    header.insert(
+        header::LOCATION,
+        HeaderValue::from_str(location).expect("invalid redirect location"),
+    );
+
+    (StatusCode::SEE_OTHER, header)
+}
@@ -0,0 +1,169 @@
+//! Liveness and readiness probes.
+//!
+//! `/healthz` only tells that the process is serving requests. `/readyz` also
+//! checks that the database answers and that no migration is pending, and
+//! returns 503 when any component is down, or once shutdown has started, so
+//! orchestrators stop routing traffic to this instance.
+
+use std::collections::BTreeMap;
+
+use axum::{extract::Extension, response::IntoResponse, Json};
+use hyper::StatusCode;
+use migration::{Migrator, MigratorTrait};
+use sea_orm::{ConnectionTrait, DatabaseConnection, Statement};
+use serde::Serialize;
+
+use crate::shutdown::Shutdown;
+
+/// Detail of a failed check; the error itself, which may name hosts or
+/// files, only goes to the logs.
+const DATABASE_UNAVAILABLE: &str = "database unavailable";
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    status: Status,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    components: BTreeMap<&'static str, Component>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Component {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+impl Component {
+    fn up(detail: Option<String>) -> Self {
+        Self {
+            status: Status::Up,
+            detail,
+        }
+    }
+
+    fn down(detail: String) -> Self {
+        Self {
+            status: Status::Down,
+            detail: Some(detail),
+        }
+    }
+}
+
+impl Health {
+    fn new(components: BTreeMap<&'static str, Component>) -> Self {
+        let status = if components.values().all(|c| c.status == Status::Up) {
+            Status::Up
+        } else {
+            Status::Down
+        };
+        Self { status, components }
+    }
+}
+
+impl IntoResponse for Health {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self.status {
+            Status::Up => StatusCode::OK,
+            Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+// curl http://localhost:8000/healthz
+pub async fn healthz() -> Health {
+    Health::new(BTreeMap::new())
+}
+
+// curl http://localhost:8000/readyz
+pub async fn readyz(
+    Extension(ref conn): Extension<DatabaseConnection>,
+    shutdown: Option<Extension<Shutdown>>,
+) -> Health {
+    let mut components = BTreeMap::new();
+    if let Some(Extension(shutdown)) = shutdown {
+        if shutdown.is_draining() {
+            components.insert("server", Component::down("shutting down".to_owned()));
+        }
+    }
+    components.insert("database", check_database(conn).await);
+    components.insert("migrations", check_migrations(conn).await);
+
+    Health::new(components)
+}
+
+async fn check_database(conn: &DatabaseConnection) -> Component {
+    let stmt = Statement::from_string(conn.get_database_backend(), "SELECT 1".to_owned());
+    match conn.execute(stmt).await {
+        Ok(_) => Component::up(None),
+        Err(e) => {
+            tracing::warn!("database check failed: {}", e);
+            Component::down(DATABASE_UNAVAILABLE.to_owned())
+        }
+    }
+}
+
+async fn check_migrations(conn: &DatabaseConnection) -> Component {
+    match Migrator::get_pending_migrations(conn).await {
+        Ok(pending) if pending.is_empty() => Component::up(None),
+        Ok(pending) => Component::down(format!("{} pending migration(s)", pending.len())),
+        Err(e) => {
+            tracing::warn!("migrations check failed: {}", e);
+            Component::down(DATABASE_UNAVAILABLE.to_owned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_app::TestApp;
+
+    #[tokio::test]
+    async fn database_errors_stay_in_the_logs() {
+        let (conn, pool) = crate::db::connect("sqlite::memory:").await.unwrap();
+        pool.close().await;
+        for component in [check_database(&conn).await, check_migrations(&conn).await] {
+            assert_eq!(component.status, Status::Down);
+            assert_eq!(component.detail.as_deref(), Some(DATABASE_UNAVAILABLE));
+        }
+    }
+
+    #[tokio::test]
+    async fn health_and_readiness() {
+        let app = TestApp::new().await;
+
+        let response = app
+            .get("/healthz")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(response.json(), json!({"status": "up"}));
+
+        let response = app
+            .get("/readyz")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(
+            response.json(),
+            json!({
+                "status": "up",
+                "components": {
+                    "database": {"status": "up"},
+                    "migrations": {"status": "up"},
+                },
+            })
+        );
+    }
+}
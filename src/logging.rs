@@ -0,0 +1,101 @@
+//! Tracing subscriber setup and the per-request span.
+//!
+//! - `LOG_LEVEL` (falls back to `RUST_LOG`, then `info`) takes any
+//!   `EnvFilter` directive, e.g. `info,sea_orm=debug`
+//! - `LOG_FORMAT` is `pretty` (default) or `json`
+
+use std::env;
+use std::str::FromStr;
+
+use axum::{body::Body, http::Request};
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format {:?}", other)),
+        }
+    }
+}
+
+pub fn init() {
+    let filter = env::var("LOG_LEVEL")
+        .or_else(|_| env::var("RUST_LOG"))
+        .unwrap_or_else(|_| "info".to_owned());
+    let format = env::var("LOG_FORMAT")
+        .ok()
+        .map(|format| format.parse().expect("LOG_FORMAT must be pretty or json"))
+        .unwrap_or(LogFormat::Pretty);
+
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::new(filter));
+    match format {
+        LogFormat::Pretty => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Open the span every request is handled in. `user` is filled in by the
+/// `Claims` extractor once the caller is authenticated.
+pub fn make_span(request: &Request<Body>) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        uri = %request.uri(),
+        user = tracing::field::Empty,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header;
+
+    use super::*;
+    use crate::test_app::TestApp;
+
+    #[test]
+    fn parse_log_format() {
+        assert_eq!("pretty".parse(), Ok(LogFormat::Pretty));
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[tokio::test]
+    async fn request_id() {
+        let app = TestApp::new().await;
+
+        let response = app.get("/hello/").send().await;
+        assert!(response.headers.contains_key(REQUEST_ID_HEADER));
+
+        let response = app
+            .get("/hello/")
+            .header(
+                header::HeaderName::from_static(REQUEST_ID_HEADER),
+                "abc-123",
+            )
+            .send()
+            .await;
+        assert_eq!(
+            response.header(header::HeaderName::from_static(REQUEST_ID_HEADER)),
+            "abc-123"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Outgoing email behind the `Mailer` trait.
+//!
+//! `MAILER=smtp` sends through `SMTP_HOST` (`SMTP_PORT`, default 587) with
+//! `SMTP_TLS` set to `starttls` (default), `tls` or `none`, logging in with
+//! `SMTP_USERNAME`/`SMTP_PASSWORD` when set. Otherwise every email is written
+//! to a `.eml` file in `MAIL_DIR` (default `mail`) for local development.
+//! Emails come from `MAIL_FROM`. Tests use `InMemoryMailer`.
+
+use std::env;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use axum::async_trait;
+use chrono::Utc;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+
+pub type DynMailer = Arc<dyn Mailer>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> anyhow::Result<()>;
+}
+
+/// The mailer selected by `MAILER`.
+pub fn from_env() -> anyhow::Result<DynMailer> {
+    let from = env::var("MAIL_FROM").unwrap_or_else(|_| "noreply@localhost".to_owned());
+    match env::var("MAILER").as_deref() {
+        Ok("smtp") => Ok(Arc::new(SmtpMailer::from_env(&from)?)),
+        Ok("file") | Err(_) => {
+            let dir = env::var("MAIL_DIR").unwrap_or_else(|_| "mail".to_owned());
+            Ok(Arc::new(FileMailer::new(dir, from)))
+        }
+        Ok(other) => Err(anyhow!("unknown MAILER {:?}, expected smtp or file", other)),
+    }
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn from_env(from: &str) -> anyhow::Result<Self> {
+        let host = env::var("SMTP_HOST").context("SMTP_HOST must be set")?;
+        let port = match env::var("SMTP_PORT") {
+            Ok(port) => port.parse().context("SMTP_PORT must be a port number")?,
+            Err(_) => 587,
+        };
+        let builder = match env::var("SMTP_TLS").as_deref() {
+            Ok("starttls") | Err(_) => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+            Ok("tls") => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+            Ok("none") => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+            Ok(other) => return Err(anyhow!("unknown SMTP_TLS {:?}", other)),
+        };
+        let mut builder = builder.port(port);
+        if let (Ok(username), Ok(password)) = (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from.parse().context("MAIL_FROM must be an email address")?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse()?)
+            .subject(email.subject)
+            .body(email.body)?;
+        self.transport.send(message).await?;
+
+        Ok(())
+    }
+}
+
+/// Writes each email to its own file, to be read instead of sent.
+pub struct FileMailer {
+    dir: PathBuf,
+    from: String,
+    count: AtomicUsize,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>, from: String) -> Self {
+        Self {
+            dir: dir.into(),
+            from,
+            count: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            self.count.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = self.dir.join(name);
+        let content = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}",
+            self.from, email.to, email.subject, email.body
+        );
+        tokio::fs::write(&path, content).await?;
+        tracing::info!(to = %email.to, path = %path.display(), "email written");
+
+        Ok(())
+    }
+}
+
+/// Keeps sent emails in memory, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct InMemoryMailer {
+    sent: std::sync::Mutex<Vec<Email>>,
+}
+
+#[cfg(test)]
+impl InMemoryMailer {
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Mailer for InMemoryMailer {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        self.sent.lock().unwrap().push(email);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_mailer_writes_one_file_per_email() {
+        let dir = env::temp_dir().join(format!("mailer-test-{}", std::process::id()));
+        let mailer = FileMailer::new(&dir, "noreply@localhost".to_owned());
+        for subject in ["first", "second"] {
+            mailer
+                .send(Email {
+                    to: "account@example.com".to_owned(),
+                    subject: subject.to_owned(),
+                    body: "Hello".to_owned(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        files.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0],
+            "From: noreply@localhost\r\nTo: account@example.com\r\nSubject: first\r\n\r\nHello"
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Prometheus metrics, scraped from `GET /metrics`.
+
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::MatchedPath,
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::StatusCode;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::db::DbPool;
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Number of HTTP requests handled",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency in seconds",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref AUTH_ATTEMPTS: IntCounterVec = register_int_counter_vec!(
+        "auth_attempts_total",
+        "Authentication attempts by source (authorize, bearer or session) and result",
+        &["source", "result"]
+    )
+    .unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "db_pool_connections",
+        "Connections currently held by the database pool"
+    )
+    .unwrap();
+    static ref DB_POOL_IDLE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "db_pool_idle_connections",
+        "Idle connections in the database pool"
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuthSource {
+    /// Credentials exchanged at `/authorize`
+    Authorize,
+    /// Bearer token presented to a protected route
+    Bearer,
+    /// Token carried by the browser session cookie
+    Session,
+    /// Personal API key sent as bearer token
+    ApiKey,
+}
+
+pub fn record_auth(source: AuthSource, success: bool) {
+    let source = match source {
+        AuthSource::Authorize => "authorize",
+        AuthSource::Bearer => "bearer",
+        AuthSource::Session => "session",
+        AuthSource::ApiKey => "api_key",
+    };
+    let result = if success { "success" } else { "failure" };
+    AUTH_ATTEMPTS.with_label_values(&[source, result]).inc();
+}
+
+/// Count and time every request, labelled by its route template rather than
+/// the raw path so that ids don't explode the label cardinality.
+pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let method = req.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+    let latency = start.elapsed().as_secs_f64();
+
+    let status = response.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(latency);
+
+    response
+}
+
+/// Refresh the pool gauges every few seconds until `token` is cancelled.
+pub async fn sample_pool(pool: DbPool, token: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = interval.tick() => {
+                DB_POOL_CONNECTIONS.set(pool.size() as i64);
+                DB_POOL_IDLE_CONNECTIONS.set(pool.num_idle() as i64);
+            }
+        }
+    }
+}
+
+// curl http://localhost:8000/metrics
+pub async fn metrics() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("could not encode metrics: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        buffer,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use crate::test_app::TestApp;
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let app = TestApp::new().await;
+
+        app.get("/hello/")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+
+        let body = app
+            .get("/metrics")
+            .send()
+            .await
+            .assert_status(StatusCode::OK)
+            .text();
+        assert!(body.contains(r#"http_requests_total{method="GET",route="/hello/",status="200"}"#));
+    }
+}
@@ -0,0 +1,744 @@
+//! Two-factor authentication with TOTP (RFC 6238).
+//!
+//! Users enroll with `POST /mfa/totp`, which returns a new secret and the
+//! `otpauth://` URI authenticator apps read from a QR code, then prove their
+//! app is set up by sending a code to `POST /mfa/totp/confirm`. Confirming
+//! turns two-factor authentication on, revokes the user's refresh tokens and
+//! API keys and returns ten recovery codes, each standing in for a TOTP code
+//! once. Only their hashes are stored, in `recovery_code`. API keys can't
+//! enroll or confirm.
+//!
+//! From then on `/authorize` answers the right secret with an `mfa_required`
+//! challenge instead of an access token. The challenge token is exchanged at
+//! `POST /authorize/mfa` together with a TOTP or recovery code within five
+//! minutes. Each challenge takes a single code, right or wrong, and wrong
+//! codes count towards the account lockout like wrong secrets do.
+//!
+//! Codes have six digits and change every 30 seconds; codes of the previous
+//! and next step are accepted for clock drift, but none twice.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{ConnectInfo, Extension},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use entity::api_key::{self, Entity as ApiKey};
+use entity::recovery_code::{self, Entity as RecoveryCode};
+use entity::refresh_token::{self, Entity as RefreshToken};
+use entity::user::{self, Entity as User};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use sea_orm::{prelude::*, sea_query::Expr, Condition, Set, TransactionTrait};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::account::{self, AccountError, Purpose};
+use crate::audit;
+use crate::oauth::TokenConfig;
+use crate::post_service::{hash_secret, issue_token, AuthBody, AuthError, Claims};
+use crate::rate_limit::{self, RateLimiter};
+
+/// Name authenticator apps show next to the account
+const ISSUER: &str = "SeaORM Axum example";
+/// Seconds each code is valid for
+const STEP: i64 = 30;
+const DIGITS: usize = 6;
+/// Steps before and after the current one whose codes are accepted
+const SKEW: i64 = 1;
+/// 160 bits, the size of an HMAC-SHA1 key (RFC 4226, section 4)
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, ToSchema)]
+pub struct TotpEnrollment {
+    /// Base32 secret, for apps that can't scan the URI
+    secret: String,
+    /// `otpauth://totp/...` URI to show as a QR code
+    otpauth_uri: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TotpCode {
+    /// Code shown by the authenticator app
+    code: String,
+}
+
+/// Recovery codes, the only time they are shown
+#[derive(Serialize, ToSchema)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Answer of `/authorize` for users with two-factor authentication
+#[derive(Serialize, ToSchema)]
+pub struct MfaChallenge {
+    /// Always `true`
+    mfa_required: bool,
+    /// Token to send to `/authorize/mfa` along with a code
+    challenge_token: String,
+    /// Lifetime of the challenge token in seconds
+    expires_in: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MfaCompletion {
+    challenge_token: String,
+    /// TOTP code or recovery code
+    code: String,
+}
+
+// curl -X POST -H 'Authorization: Bearer ...' http://localhost:8000/mfa/totp
+#[utoipa::path(
+    post,
+    path = "/mfa/totp",
+    tag = "mfa",
+    responses(
+        (status = 200, description = "New secret, active once confirmed", body = TotpEnrollment),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Authenticated with an API key", body = ErrorBody),
+        (status = 409, description = "Two-factor authentication already enabled", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn enroll_totp(
+    claims: Claims,
+    Extension(ref conn): Extension<DatabaseConnection>,
+) -> Result<Json<TotpEnrollment>, MfaError> {
+    require_token(&claims)?;
+    let user = current_user(conn, &claims).await?;
+    if user.totp_enabled_at.is_some() {
+        return Err(MfaError::AlreadyEnabled);
+    }
+
+    // Enrolling again replaces a secret that was never confirmed
+    let secret = base32_encode(&random::<SECRET_LEN>()?);
+    let otpauth_uri = otpauth_uri(&user.email, &secret);
+    let mut model: user::ActiveModel = user.clone().into();
+    model.totp_secret = Set(Some(secret.clone()));
+    let updated = model.update(conn).await?;
+    audit::updated(conn, Some(claims.sub()), &user, &updated).await?;
+    tracing::info!(user_id = user.id, "totp enrollment started");
+
+    Ok(Json(TotpEnrollment {
+        secret,
+        otpauth_uri,
+    }))
+}
+
+// curl -H 'Authorization: Bearer ...' -H 'Content-Type: application/json' http://localhost:8000/mfa/totp/confirm --data '{"code":"123456"}'
+#[utoipa::path(
+    post,
+    path = "/mfa/totp/confirm",
+    tag = "mfa",
+    request_body = TotpCode,
+    responses(
+        (status = 200, description = "Two-factor authentication enabled; the recovery codes are not shown again", body = RecoveryCodes),
+        (status = 400, description = "Invalid code, or no enrollment started", body = ErrorBody),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Authenticated with an API key", body = ErrorBody),
+        (status = 409, description = "Two-factor authentication already enabled", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn confirm_totp(
+    claims: Claims,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Json(input): Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, MfaError> {
+    require_token(&claims)?;
+    let user = current_user(conn, &claims).await?;
+    if user.totp_enabled_at.is_some() {
+        return Err(MfaError::AlreadyEnabled);
+    }
+    let secret = user
+        .totp_secret
+        .as_deref()
+        .and_then(base32_decode)
+        .ok_or(MfaError::NotEnrolled)?;
+    let step =
+        verify_totp(&secret, &input.code, Utc::now().timestamp()).ok_or(MfaError::InvalidCode)?;
+
+    let user_id = user.id;
+    let txn = conn.begin().await?;
+    let mut model: user::ActiveModel = user.clone().into();
+    model.totp_enabled_at = Set(Some(Utc::now()));
+    model.totp_last_step = Set(Some(step));
+    let updated = model.update(&txn).await?;
+    audit::updated(&txn, Some(claims.sub()), &user, &updated).await?;
+
+    RecoveryCode::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await?;
+    let mut recovery_codes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        let code = new_recovery_code()?;
+        recovery_code::ActiveModel {
+            user_id: Set(user_id),
+            hash: Set(hash_secret(&normalize_recovery_code(&code))),
+            ..Default::default()
+        }
+        .insert(&txn)
+        .await?;
+        recovery_codes.push(code);
+    }
+    // Refresh tokens and API keys were handed out on the secret alone
+    RefreshToken::delete_many()
+        .filter(refresh_token::Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await?;
+    ApiKey::delete_many()
+        .filter(api_key::Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await?;
+    txn.commit().await?;
+    tracing::info!(user_id, "totp enabled");
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+// curl -H 'Content-Type: application/json' http://localhost:8000/authorize/mfa --data '{"challenge_token":"...","code":"123456"}'
+#[utoipa::path(
+    post,
+    path = "/authorize/mfa",
+    tag = "auth",
+    request_body = MfaCompletion,
+    responses(
+        (status = 200, description = "Access token to send as `Authorization: Bearer`", body = AuthBody),
+        (status = 400, description = "Invalid, used or expired challenge token, or invalid code", body = ErrorBody),
+        (status = 429, description = "Too many attempts, retry after `Retry-After` seconds", body = ErrorBody),
+    )
+)]
+pub async fn complete_authorization(
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Extension(limiter): Extension<Arc<RateLimiter>>,
+    Extension(config): Extension<TokenConfig>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(input): Json<MfaCompletion>,
+) -> Result<Json<AuthBody>, MfaError> {
+    let user = account::redeem(conn, &input.challenge_token, Purpose::MfaChallenge).await?;
+    let ip = rate_limit::client_ip(connect_info.as_ref());
+    limiter.check_login(&ip, &user.email).await?;
+
+    let result = match check_code(conn, &user, &input.code).await? {
+        true => Ok(()),
+        false => Err(AuthError::WrongCredentials),
+    };
+    limiter
+        .record_login(&user.email, result)
+        .await
+        .map_err(|e| match e {
+            AuthError::WrongCredentials => MfaError::InvalidCode,
+            e => MfaError::Auth(e),
+        })?;
+    let token = issue_token(&user, &config)?;
+
+    Ok(Json(AuthBody::new(token)))
+}
+
+/// Challenge to answer `/authorize` with once `user` gave the right secret.
+pub async fn challenge(
+    conn: &DatabaseConnection,
+    user: &user::Model,
+) -> Result<MfaChallenge, AuthError> {
+    let challenge_token = account::issue(conn, user, Purpose::MfaChallenge, CHALLENGE_LIFETIME)
+        .await
+        .map_err(|e| {
+            tracing::error!(user_id = user.id, "could not issue mfa challenge: {:?}", e);
+            AuthError::TokenCreation
+        })?;
+
+    Ok(MfaChallenge {
+        mfa_required: true,
+        challenge_token,
+        expires_in: CHALLENGE_LIFETIME.as_secs(),
+    })
+}
+
+/// Whether `code` is a current TOTP code or an unused recovery code of
+/// `user`, using it up either way.
+pub async fn check_code(
+    conn: &DatabaseConnection,
+    user: &user::Model,
+    code: &str,
+) -> Result<bool, DbErr> {
+    let secret = match user.totp_secret.as_deref().and_then(base32_decode) {
+        Some(secret) if user.totp_enabled_at.is_some() => secret,
+        _ => return Ok(false),
+    };
+    if let Some(step) = verify_totp(&secret, code, Utc::now().timestamp()) {
+        // Only moving the last step forward accepts the code, so of two
+        // requests with the same code only one gets through
+        let result = User::update_many()
+            .col_expr(user::Column::TotpLastStep, Expr::value(step))
+            .filter(user::Column::Id.eq(user.id))
+            .filter(
+                Condition::any()
+                    .add(user::Column::TotpLastStep.is_null())
+                    .add(user::Column::TotpLastStep.lt(step)),
+            )
+            .exec(conn)
+            .await?;
+        return Ok(result.rows_affected == 1);
+    }
+
+    let result = RecoveryCode::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user.id))
+        .filter(recovery_code::Column::Hash.eq(hash_secret(&normalize_recovery_code(code))))
+        .exec(conn)
+        .await?;
+    if result.rows_affected > 0 {
+        tracing::info!(user_id = user.id, "recovery code used");
+    }
+
+    Ok(result.rows_affected > 0)
+}
+
+fn require_token(claims: &Claims) -> Result<(), MfaError> {
+    if claims.is_api_key() {
+        return Err(MfaError::KeyAuthenticated);
+    }
+
+    Ok(())
+}
+
+async fn current_user(conn: &DatabaseConnection, claims: &Claims) -> Result<user::Model, MfaError> {
+    User::find()
+        .filter(user::Column::Email.eq(claims.sub()))
+        .one(conn)
+        .await?
+        .ok_or(MfaError::Auth(AuthError::InvalidToken))
+}
+
+fn random<const N: usize>() -> Result<[u8; N], MfaError> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| MfaError::Auth(AuthError::TokenCreation))?;
+    Ok(bytes)
+}
+
+/// Sixteen base32 characters in groups of four, e.g. `abcd-efgh-ijkl-mnop`.
+fn new_recovery_code() -> Result<String, MfaError> {
+    let code = base32_encode(&random::<10>()?).to_ascii_lowercase();
+    let groups: Vec<_> = code
+        .as_bytes()
+        .chunks(4)
+        .map(String::from_utf8_lossy)
+        .collect();
+    Ok(groups.join("-"))
+}
+
+/// Recovery codes are compared without case, dashes or spaces.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// HOTP value of `counter` (RFC 4226, section 5.3), before truncation to
+/// `DIGITS` digits.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let hash = tag.as_ref();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&hash[offset..offset + 4]);
+    u32::from_be_bytes(value) & 0x7fff_ffff
+}
+
+fn totp(secret: &[u8], step: i64) -> String {
+    let code = hotp(secret, step as u64) % 10u32.pow(DIGITS as u32);
+    format!("{:0width$}", code, width = DIGITS)
+}
+
+/// Time step `code` belongs to, if it is valid for `secret` at the UNIX time
+/// `now`.
+fn verify_totp(secret: &[u8], code: &str, now: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let current = now.div_euclid(STEP);
+    (current - SKEW..=current + SKEW).find(|&step| {
+        ring::constant_time::verify_slices_are_equal(totp(secret, step).as_bytes(), code.as_bytes())
+            .is_ok()
+    })
+}
+
+/// Key URI of the Google Authenticator format, understood by most apps.
+fn otpauth_uri(email: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        uri_encode(ISSUER),
+        uri_encode(email),
+        secret,
+        uri_encode(ISSUER),
+        DIGITS,
+        STEP
+    )
+}
+
+/// Percent-encode everything but unreserved characters (RFC 3986).
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Base32 without padding (RFC 4648, section 6), as in `otpauth` URIs.
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            text.push(BASE32[((buffer >> (bits - 5)) & 31) as usize] as char);
+            bits -= 5;
+        }
+    }
+    if bits > 0 {
+        text.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    text
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bytes.push((buffer >> (bits - 8)) as u8);
+            bits -= 8;
+        }
+    }
+    Some(bytes)
+}
+
+#[derive(Debug)]
+pub enum MfaError {
+    AlreadyEnabled,
+    /// Confirming before enrolling
+    NotEnrolled,
+    InvalidCode,
+    /// Two-factor authentication cannot be managed with an API key
+    KeyAuthenticated,
+    Account(AccountError),
+    Auth(AuthError),
+    Database(DbErr),
+}
+
+impl From<DbErr> for MfaError {
+    fn from(err: DbErr) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl From<AuthError> for MfaError {
+    fn from(err: AuthError) -> Self {
+        Self::Auth(err)
+    }
+}
+
+impl From<AccountError> for MfaError {
+    fn from(err: AccountError) -> Self {
+        Self::Account(err)
+    }
+}
+
+impl IntoResponse for MfaError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            MfaError::AlreadyEnabled => (
+                StatusCode::CONFLICT,
+                "Two-factor authentication already enabled",
+            ),
+            MfaError::NotEnrolled => (StatusCode::BAD_REQUEST, "No enrollment started"),
+            MfaError::KeyAuthenticated => (
+                StatusCode::FORBIDDEN,
+                "API keys cannot manage two-factor authentication",
+            ),
+            MfaError::InvalidCode => (StatusCode::BAD_REQUEST, "Invalid code"),
+            MfaError::Account(err) => return err.into_response(),
+            MfaError::Auth(err) => return err.into_response(),
+            MfaError::Database(err) => {
+                tracing::error!("database error: {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+            }
+        };
+        let body = Json(json!({
+            "error": error_message,
+        }));
+        (status, body).into_response()
+    }
+}
+
+/// The current code of a user's secret, for tests driving the login flow.
+#[cfg(test)]
+pub fn current_code(secret: &str) -> String {
+    let secret = base32_decode(secret).unwrap();
+    totp(&secret, Utc::now().timestamp().div_euclid(STEP))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::rate_limit::RateLimitConfig;
+    use crate::test_app::{TestApp, EMAIL, SECRET};
+
+    #[test]
+    fn base32() {
+        // RFC 4648, section 10
+        for (bytes, text) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32_encode(bytes.as_bytes()), text);
+            assert_eq!(base32_decode(text).unwrap(), bytes.as_bytes());
+        }
+        assert_eq!(base32_decode("mzxw6ytboi").unwrap(), b"foobar");
+        assert_eq!(base32_decode("MZXW1"), None);
+    }
+
+    #[test]
+    fn totp_test_vectors() {
+        // RFC 6238, appendix B, SHA1, keeping the last six of eight digits
+        let secret = b"12345678901234567890";
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(totp(secret, time / STEP), code);
+            assert_eq!(verify_totp(secret, code, time), Some(time / STEP));
+        }
+    }
+
+    #[test]
+    fn verify_totp_accepts_one_step_of_drift() {
+        let secret = b"12345678901234567890";
+        let now = 1111111111;
+        let step = now / STEP;
+        assert_eq!(
+            verify_totp(secret, &totp(secret, step - 1), now),
+            Some(step - 1)
+        );
+        assert_eq!(
+            verify_totp(secret, &totp(secret, step + 1), now),
+            Some(step + 1)
+        );
+        assert_eq!(verify_totp(secret, &totp(secret, step - 2), now), None);
+        assert_eq!(verify_totp(secret, " 050471 ", now), Some(step));
+        assert_eq!(verify_totp(secret, "50471", now), None);
+        assert_eq!(verify_totp(secret, "05047a", now), None);
+    }
+
+    #[test]
+    fn otpauth_uris() {
+        assert_eq!(
+            otpauth_uri("account@example.com", "JBSWY3DPEHPK3PXP"),
+            "otpauth://totp/SeaORM%20Axum%20example:account%40example.com\
+             ?secret=JBSWY3DPEHPK3PXP&issuer=SeaORM%20Axum%20example\
+             &algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn recovery_codes() {
+        let code = new_recovery_code().unwrap();
+        assert_eq!(code.len(), 19);
+        assert_eq!(code.matches('-').count(), 3);
+        assert_eq!(
+            normalize_recovery_code(&code.to_uppercase().replace('-', " ")),
+            code.replace('-', "")
+        );
+    }
+
+    #[tokio::test]
+    async fn api_keys_cannot_manage_totp() {
+        let app = TestApp::new().await;
+        let key = app.api_key(&app.token().await).await;
+
+        app.post("/mfa/totp")
+            .bearer(&key)
+            .send()
+            .await
+            .assert_error(
+                StatusCode::FORBIDDEN,
+                "API keys cannot manage two-factor authentication",
+            );
+        app.post("/mfa/totp/confirm")
+            .bearer(&key)
+            .json(json!({ "code": "123456" }))
+            .send()
+            .await
+            .assert_error(
+                StatusCode::FORBIDDEN,
+                "API keys cannot manage two-factor authentication",
+            );
+    }
+
+    #[tokio::test]
+    async fn totp_two_factor_authentication() {
+        // Every login below takes two attempts from the account's quota
+        let app = TestApp::with_rate_limits(RateLimitConfig {
+            login_per_account: None,
+            ..RateLimitConfig::default()
+        })
+        .await;
+        let token = app.token().await;
+        let confirm = |code: &str| {
+            app.post("/mfa/totp/confirm")
+                .bearer(&token)
+                .json(json!({ "code": code }))
+                .send()
+        };
+        let complete = |challenge: &Value, code: &str| {
+            app.post("/authorize/mfa")
+                .json(json!({"challenge_token": challenge["challenge_token"], "code": code}))
+                .send()
+        };
+
+        confirm("123456")
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "No enrollment started");
+        let enrollment = app
+            .post("/mfa/totp")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK)
+            .json();
+        let secret = enrollment["secret"].as_str().unwrap();
+        assert!(enrollment["otpauth_uri"]
+            .as_str()
+            .unwrap()
+            .starts_with("otpauth://totp/"));
+        assert!(enrollment["otpauth_uri"]
+            .as_str()
+            .unwrap()
+            .contains(&format!("secret={}", secret)));
+
+        // Still a single step until confirmed
+        let response = app
+            .authorize(EMAIL, SECRET)
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(response.json()["access_token"].is_string());
+
+        let code = current_code(secret);
+        let wrong = format!(
+            "{:06}",
+            (code.parse::<u32>().unwrap() + 500_000) % 1_000_000
+        );
+        confirm(&wrong)
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Invalid code");
+        let key = app.api_key(&token).await;
+        let recovery_codes = confirm(&code).await.assert_status(StatusCode::OK).json()
+            ["recovery_codes"]
+            .as_array()
+            .unwrap()
+            .clone();
+        assert_eq!(recovery_codes.len(), 10);
+        app.get("/api/")
+            .bearer(&key)
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+        app.post("/mfa/totp")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_error(
+                StatusCode::CONFLICT,
+                "Two-factor authentication already enabled",
+            );
+
+        // The secret alone now only gets a challenge
+        let challenge = app.authorize(EMAIL, SECRET).await.json();
+        assert_eq!(challenge["mfa_required"], true);
+        assert_eq!(challenge["expires_in"], 300);
+        assert!(challenge.get("access_token").is_none());
+        // A code works once, even across the enrollment and logins
+        complete(&challenge, &code)
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Invalid code");
+        // and so does a challenge
+        complete(&challenge, recovery_codes[0].as_str().unwrap())
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Invalid or expired token");
+
+        let challenge = app.authorize(EMAIL, SECRET).await.json();
+        let response = complete(&challenge, recovery_codes[0].as_str().unwrap())
+            .await
+            .assert_status(StatusCode::OK);
+        let access_token = response.json()["access_token"].as_str().unwrap().to_owned();
+        app.get("/api/")
+            .bearer(&access_token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        let challenge = app.authorize(EMAIL, SECRET).await.json();
+        complete(&challenge, recovery_codes[0].as_str().unwrap())
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Invalid code");
+
+        // Pretend the code of the enrollment is from long ago
+        entity::user::Entity::update_many()
+            .col_expr(
+                entity::user::Column::TotpLastStep,
+                sea_orm::sea_query::Expr::value(0i64),
+            )
+            .exec(&app.conn)
+            .await
+            .unwrap();
+        let challenge = app.authorize(EMAIL, SECRET).await.json();
+        complete(&challenge, &code)
+            .await
+            .assert_status(StatusCode::OK);
+
+        let response = app
+            .post("/oauth/token")
+            .form("grant_type=password&username=account%40example.com&password=secret".to_owned())
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        assert_eq!(response.json()["error"], "invalid_grant");
+    }
+}
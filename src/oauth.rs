@@ -0,0 +1,649 @@
+//! OAuth 2.0 token endpoint (RFC 6749, section 3.2) for off-the-shelf clients.
+//!
+//! `POST /oauth/token` takes an `application/x-www-form-urlencoded` body and
+//! supports three grants:
+//!
+//! - `client_credentials`: the client is a user account authenticating with
+//!   its email and secret, either as `client_id`/`client_secret` fields or with
+//!   HTTP Basic auth. No refresh token is issued.
+//! - `password`: `username` and `password` of a user; answered with an access
+//!   token and a refresh token.
+//! - `refresh_token`: trades a refresh token for a new pair. Each refresh
+//!   token works once, and `scope` may only narrow the one originally granted.
+//!
+//! Without a `scope` parameter tokens carry all the scopes the user is allowed
+//! (`user.scopes`); requesting any other scope is an `invalid_scope` error.
+//!
+//! Access tokens are the JWTs `/authorize` hands out, except that they expire
+//! after `ACCESS_TOKEN_SECONDS` (default an hour). Refresh tokens are random
+//! strings kept hashed in the `refresh_token` table for `REFRESH_TOKEN_SECONDS`
+//! (default 30 days). Errors carry the codes of RFC 6749, section 5.2.
+//!
+//! Users with two-factor authentication (see `mfa`) can't use the
+//! `client_credentials` and `password` grants.
+//!
+//! There is no registry of clients besides the users, so client credentials
+//! sent along with the `password` and `refresh_token` grants are ignored.
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{rejection::FormRejection, ConnectInfo, Extension, Form, TypedHeader},
+    headers::{authorization::Basic, Authorization},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use entity::refresh_token::{self, Entity as RefreshToken};
+use entity::user::{self, Entity as User};
+use ring::rand::{SecureRandom, SystemRandom};
+use sea_orm::{prelude::*, Set};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::post_service::{hash_secret, issue_token_expiring_in, verify_credentials, AuthError};
+use crate::rate_limit::{self, RateLimiter};
+use crate::scope;
+
+/// Token responses must never be cached (RFC 6749, section 5.1).
+const NO_STORE: [(HeaderName, &str); 2] = [
+    (header::CACHE_CONTROL, "no-store"),
+    (header::PRAGMA, "no-cache"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenConfig {
+    pub access_lifetime: Duration,
+    pub refresh_lifetime: Duration,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            access_lifetime: Duration::from_secs(60 * 60),
+            refresh_lifetime: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl TokenConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let seconds = |name: &str, default: Duration| {
+            env::var(name)
+                .ok()
+                .map(|value| {
+                    value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("{} must be a number of seconds", name))
+                })
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+
+        Self {
+            access_lifetime: seconds("ACCESS_TOKEN_SECONDS", default.access_lifetime),
+            refresh_lifetime: seconds("REFRESH_TOKEN_SECONDS", default.refresh_lifetime),
+        }
+    }
+}
+
+/// Form fields of a token request; which ones are required depends on
+/// `grant_type`.
+#[derive(Deserialize, ToSchema)]
+pub struct TokenRequest {
+    /// `client_credentials`, `password` or `refresh_token`
+    grant_type: Option<String>,
+    /// Email of the user, unless sent with HTTP Basic auth
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    /// Email of the user for the `password` grant
+    username: Option<String>,
+    password: Option<String>,
+    refresh_token: Option<String>,
+    /// Space-separated scopes to request
+    scope: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokenResponse {
+    access_token: String,
+    token_type: String,
+    /// Lifetime of the access token in seconds
+    expires_in: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    /// Space-separated scopes granted
+    scope: String,
+}
+
+/// Error response of RFC 6749, section 5.2
+#[derive(Serialize, ToSchema)]
+pub struct OAuthErrorBody {
+    error: String,
+    error_description: String,
+}
+
+#[derive(Debug)]
+pub enum OAuthError {
+    /// Missing, repeated or malformed parameter
+    InvalidRequest(&'static str),
+    /// Unknown client or wrong client secret
+    InvalidClient,
+    /// Wrong password, or an unknown, used or expired refresh token
+    InvalidGrant(&'static str),
+    UnsupportedGrantType,
+    InvalidScope,
+    /// Rate limited or locked out, retry after the given time
+    TemporarilyUnavailable(Duration),
+    ServerError,
+}
+
+// curl http://localhost:8000/oauth/token -d grant_type=password -d username=account@example.com -d password=secret
+#[utoipa::path(
+    post,
+    path = "/oauth/token",
+    tag = "auth",
+    request_body(content = TokenRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "Access token to send as `Authorization: Bearer`", body = TokenResponse),
+        (status = 400, description = "`invalid_request`, `invalid_grant`, `unsupported_grant_type` or `invalid_scope`", body = OAuthErrorBody),
+        (status = 401, description = "`invalid_client`", body = OAuthErrorBody),
+        (status = 429, description = "Too many attempts, retry after `Retry-After` seconds", body = OAuthErrorBody),
+    )
+)]
+pub async fn token(
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Extension(limiter): Extension<Arc<RateLimiter>>,
+    Extension(config): Extension<TokenConfig>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    form: Result<Form<TokenRequest>, FormRejection>,
+) -> Result<Response, OAuthError> {
+    let Form(request) =
+        form.map_err(|_| OAuthError::InvalidRequest("Expected a form-encoded body"))?;
+    let scope = parse_scope(request.scope.as_deref())?;
+    let ip = rate_limit::client_ip(connect_info.as_ref());
+
+    let response = match request.grant_type.as_deref() {
+        Some("client_credentials") => {
+            let (client_id, client_secret) = client_credentials(&request, basic)?;
+            let user = login(conn, &limiter, &ip, &client_id, &client_secret)
+                .await
+                .map_err(|e| credentials_error(e, OAuthError::InvalidClient))?;
+            without_mfa(&user)?;
+            let scope = allowed_scope(scope, &user)?;
+            grant(conn, &config, &user, scope, None).await?
+        }
+        Some("password") => {
+            let username = required(&request.username, "Missing username")?;
+            let password = required(&request.password, "Missing password")?;
+            let user = login(conn, &limiter, &ip, username, password)
+                .await
+                .map_err(|e| {
+                    credentials_error(e, OAuthError::InvalidGrant("Wrong username or password"))
+                })?;
+            without_mfa(&user)?;
+            let scope = allowed_scope(scope, &user)?;
+            grant(conn, &config, &user, scope.clone(), Some(scope)).await?
+        }
+        Some("refresh_token") => {
+            let token = required(&request.refresh_token, "Missing refresh_token")?;
+            refresh(conn, &config, token, scope).await?
+        }
+        Some(_) => return Err(OAuthError::UnsupportedGrantType),
+        None => return Err(OAuthError::InvalidRequest("Missing grant_type")),
+    };
+
+    Ok((NO_STORE, Json(response)).into_response())
+}
+
+fn required<'a>(value: &'a Option<String>, message: &'static str) -> Result<&'a str, OAuthError> {
+    value
+        .as_deref()
+        .filter(|value| !value.is_empty())
+        .ok_or(OAuthError::InvalidRequest(message))
+}
+
+/// Client id and secret from the Basic header or the form, but not both.
+fn client_credentials(
+    request: &TokenRequest,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+) -> Result<(String, String), OAuthError> {
+    match basic {
+        Some(_) if request.client_secret.is_some() => Err(OAuthError::InvalidRequest(
+            "Use only one client authentication method",
+        )),
+        // Both parts are form-encoded before being joined (RFC 6749, section 2.3.1)
+        Some(TypedHeader(Authorization(basic))) => {
+            match (form_decode(basic.username()), form_decode(basic.password())) {
+                (Some(id), Some(secret)) => Ok((id, secret)),
+                _ => Err(OAuthError::InvalidClient),
+            }
+        }
+        None => Ok((
+            required(&request.client_id, "Missing client_id")?.to_owned(),
+            required(&request.client_secret, "Missing client_secret")?.to_owned(),
+        )),
+    }
+}
+
+/// Decode `application/x-www-form-urlencoded` text, `None` if malformed.
+fn form_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut chars = value.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [chars.next()?, chars.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Validate and normalize a space-separated `scope` (RFC 6749, section 3.3).
+fn parse_scope(scope: Option<&str>) -> Result<Option<String>, OAuthError> {
+    let scope = match scope {
+        Some(scope) => scope,
+        None => return Ok(None),
+    };
+    let mut tokens: Vec<&str> = Vec::new();
+    for token in scope.split(' ') {
+        let valid = !token.is_empty()
+            && token
+                .bytes()
+                .all(|b| b == 0x21 || (0x23..=0x5b).contains(&b) || (0x5d..=0x7e).contains(&b));
+        if !valid {
+            return Err(OAuthError::InvalidScope);
+        }
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    Ok(Some(tokens.join(" ")))
+}
+
+/// The requested scope if the user may have it, or all of theirs.
+fn allowed_scope(requested: Option<String>, user: &user::Model) -> Result<String, OAuthError> {
+    match requested {
+        Some(scope) if !scope::is_subset(&scope, &user.scopes) => Err(OAuthError::InvalidScope),
+        Some(scope) => Ok(scope),
+        None => Ok(user.scopes.clone()),
+    }
+}
+
+/// The grants here take a secret alone, which is not enough for users with
+/// two-factor authentication.
+fn without_mfa(user: &user::Model) -> Result<(), OAuthError> {
+    match user.totp_enabled_at {
+        Some(_) => Err(OAuthError::InvalidGrant(
+            "Two-factor authentication is enabled, use /authorize",
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check the credentials of `account`, counting failures towards its lockout.
+async fn login(
+    conn: &DatabaseConnection,
+    limiter: &RateLimiter,
+    ip: &str,
+    account: &str,
+    secret: &str,
+) -> Result<user::Model, AuthError> {
+    limiter.check_login(ip, account).await?;
+    limiter
+        .record_login(account, verify_credentials(conn, account, secret).await)
+        .await
+}
+
+/// Map a failed login to the OAuth error, `wrong` being the one for wrong
+/// credentials in this grant.
+fn credentials_error(err: AuthError, wrong: OAuthError) -> OAuthError {
+    match err {
+        AuthError::WrongCredentials => wrong,
+        AuthError::MissingCredentials => OAuthError::InvalidRequest("Missing credentials"),
+        AuthError::TooManyAttempts(wait) => OAuthError::TemporarilyUnavailable(wait),
+        _ => OAuthError::ServerError,
+    }
+}
+
+fn server_error(err: DbErr) -> OAuthError {
+    tracing::error!("database error: {}", err);
+    OAuthError::ServerError
+}
+
+/// Issue an access token for `scope`, and a refresh token for
+/// `refresh_scope` if given.
+async fn grant(
+    conn: &DatabaseConnection,
+    config: &TokenConfig,
+    user: &user::Model,
+    scope: String,
+    refresh_scope: Option<String>,
+) -> Result<TokenResponse, OAuthError> {
+    let access_token = issue_token_expiring_in(user, &scope, config.access_lifetime)
+        .map_err(|_| OAuthError::ServerError)?;
+    let refresh_token = match refresh_scope {
+        Some(refresh_scope) => Some(store_refresh_token(conn, config, user, refresh_scope).await?),
+        None => None,
+    };
+
+    Ok(TokenResponse {
+        access_token,
+        token_type: "Bearer".to_owned(),
+        expires_in: config.access_lifetime.as_secs(),
+        refresh_token,
+        scope,
+    })
+}
+
+async fn store_refresh_token(
+    conn: &DatabaseConnection,
+    config: &TokenConfig,
+    user: &user::Model,
+    scope: String,
+) -> Result<String, OAuthError> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| OAuthError::ServerError)?;
+    let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    let lifetime =
+        chrono::Duration::from_std(config.refresh_lifetime).map_err(|_| OAuthError::ServerError)?;
+
+    refresh_token::ActiveModel {
+        user_id: Set(user.id),
+        hash: Set(hash_secret(&token)),
+        scope: Set(scope),
+        expires_at: Set(Utc::now() + lifetime),
+        ..Default::default()
+    }
+    .insert(conn)
+    .await
+    .map_err(server_error)?;
+
+    Ok(token)
+}
+
+async fn refresh(
+    conn: &DatabaseConnection,
+    config: &TokenConfig,
+    token: &str,
+    scope: Option<String>,
+) -> Result<TokenResponse, OAuthError> {
+    let stored = RefreshToken::find()
+        .filter(refresh_token::Column::Hash.eq(hash_secret(token)))
+        .one(conn)
+        .await
+        .map_err(server_error)?
+        .ok_or(OAuthError::InvalidGrant("Unknown refresh token"))?;
+    // Of two requests presenting the same token, only the one that deletes it
+    // gets a new pair
+    let deleted = RefreshToken::delete_by_id(stored.id)
+        .exec(conn)
+        .await
+        .map_err(server_error)?;
+    if deleted.rows_affected == 0 {
+        return Err(OAuthError::InvalidGrant("Unknown refresh token"));
+    }
+    if stored.expires_at <= Utc::now() {
+        return Err(OAuthError::InvalidGrant("Expired refresh token"));
+    }
+
+    let scope = match scope {
+        Some(scope) => {
+            let granted: Vec<&str> = stored.scope.split(' ').collect();
+            if !scope.split(' ').all(|token| granted.contains(&token)) {
+                return Err(OAuthError::InvalidScope);
+            }
+            scope
+        }
+        None => stored.scope.clone(),
+    };
+    let user = User::find_by_id(stored.user_id)
+        .one(conn)
+        .await
+        .map_err(server_error)?
+        .ok_or(OAuthError::InvalidGrant("Unknown refresh token"))?;
+    // The user may have lost some scopes since
+    if !scope::is_subset(&scope, &user.scopes) {
+        return Err(OAuthError::InvalidScope);
+    }
+
+    // The new refresh token keeps the scope originally granted
+    grant(conn, config, &user, scope, Some(stored.scope)).await
+}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> Response {
+        let (status, error, description) = match self {
+            OAuthError::InvalidRequest(description) => {
+                (StatusCode::BAD_REQUEST, "invalid_request", description)
+            }
+            OAuthError::InvalidClient => (
+                StatusCode::UNAUTHORIZED,
+                "invalid_client",
+                "Wrong client credentials",
+            ),
+            OAuthError::InvalidGrant(description) => {
+                (StatusCode::BAD_REQUEST, "invalid_grant", description)
+            }
+            OAuthError::UnsupportedGrantType => (
+                StatusCode::BAD_REQUEST,
+                "unsupported_grant_type",
+                "Supported grant types are client_credentials, password and refresh_token",
+            ),
+            OAuthError::InvalidScope => (
+                StatusCode::BAD_REQUEST,
+                "invalid_scope",
+                "Unknown scope or not allowed",
+            ),
+            OAuthError::TemporarilyUnavailable(_) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "temporarily_unavailable",
+                "Too many attempts",
+            ),
+            OAuthError::ServerError => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "server_error",
+                "Internal error",
+            ),
+        };
+        let body = Json(OAuthErrorBody {
+            error: error.to_owned(),
+            error_description: description.to_owned(),
+        });
+        let mut response = (status, NO_STORE, body).into_response();
+        let headers = response.headers_mut();
+        match self {
+            OAuthError::InvalidClient => {
+                headers.insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"Basic realm="oauth""#),
+                );
+            }
+            OAuthError::TemporarilyUnavailable(wait) => {
+                headers.insert(header::RETRY_AFTER, rate_limit::retry_after(wait));
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_app::TestApp;
+
+    #[test]
+    fn scopes_are_validated_and_normalized() {
+        assert_eq!(parse_scope(None).unwrap(), None);
+        assert_eq!(
+            parse_scope(Some("posts:read posts:write posts:read")).unwrap(),
+            Some("posts:read posts:write".to_owned())
+        );
+        for scope in ["", "posts:read  posts:write", "posts\"read", "posts\\read"] {
+            assert!(matches!(
+                parse_scope(Some(scope)),
+                Err(OAuthError::InvalidScope)
+            ));
+        }
+    }
+
+    #[test]
+    fn basic_credentials_are_form_decoded() {
+        assert_eq!(
+            form_decode("account%40example.com").as_deref(),
+            Some("account@example.com")
+        );
+        assert_eq!(form_decode("a+b%2Bc").as_deref(), Some("a b+c"));
+        assert_eq!(form_decode("100%"), None);
+        assert_eq!(form_decode("%zz"), None);
+    }
+
+    #[tokio::test]
+    async fn oauth_token() {
+        let app = TestApp::new().await;
+        let token = |form: &str| app.post("/oauth/token").form(form.to_owned()).send();
+
+        // - client credentials, in the form or with HTTP Basic auth
+        let response = token(
+            "grant_type=client_credentials&client_id=account%40example.com&client_secret=secret",
+        )
+        .await
+        .assert_status(StatusCode::OK);
+        assert_eq!(response.header(header::CACHE_CONTROL), "no-store");
+        let body = response.json();
+        assert_eq!(body["token_type"], "Bearer");
+        assert_eq!(body["expires_in"], 3600);
+        assert_eq!(body["scope"], "posts:read posts:write");
+        assert!(body.get("refresh_token").is_none());
+        app.get("/api/")
+            .bearer(body["access_token"].as_str().unwrap())
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+
+        let basic = format!("Basic {}", base64::encode("account%40example.com:secret"));
+        app.post("/oauth/token")
+            .header(header::AUTHORIZATION, &basic)
+            .form("grant_type=client_credentials".to_owned())
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+
+        // - password, then refresh once
+        let body = token(
+            "grant_type=password&username=account%40example.com&password=secret&scope=posts:read%20posts:read",
+        )
+        .await
+        .assert_status(StatusCode::OK)
+        .json();
+        assert_eq!(body["scope"], "posts:read");
+        let refresh_token = body["refresh_token"].as_str().unwrap().to_owned();
+
+        let refresh = format!("grant_type=refresh_token&refresh_token={}", refresh_token);
+        let body = token(&refresh).await.assert_status(StatusCode::OK).json();
+        assert_eq!(body["scope"], "posts:read");
+        assert_ne!(body["refresh_token"].as_str().unwrap(), refresh_token);
+        let response = token(&refresh).await.assert_status(StatusCode::BAD_REQUEST);
+        assert_eq!(response.json()["error"], "invalid_grant");
+
+        let refresh = format!(
+            "grant_type=refresh_token&refresh_token={}&scope=posts:read%20posts:write",
+            body["refresh_token"].as_str().unwrap()
+        );
+        let response = token(&refresh).await.assert_status(StatusCode::BAD_REQUEST);
+        assert_eq!(response.json()["error"], "invalid_scope");
+    }
+
+    #[tokio::test]
+    async fn oauth_token_errors() {
+        let app = TestApp::new().await;
+        let error = |form: &str, status| {
+            let request = app.post("/oauth/token").form(form.to_owned());
+            async move {
+                let response = request.send().await.assert_status(status);
+                assert_eq!(response.header(header::CACHE_CONTROL), "no-store");
+                response.json()["error"].as_str().unwrap().to_owned()
+            }
+        };
+
+        let response = app
+            .post("/oauth/token")
+            .form(
+                "grant_type=client_credentials&client_id=account%40example.com&client_secret=wrong"
+                    .to_owned(),
+            )
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        assert_eq!(response.json()["error"], "invalid_client");
+        assert_eq!(
+            response.header(header::WWW_AUTHENTICATE),
+            r#"Basic realm="oauth""#
+        );
+
+        for (form, status, code) in [
+            (
+                "grant_type=password&username=account%40example.com&password=wrong",
+                StatusCode::BAD_REQUEST,
+                "invalid_grant",
+            ),
+            (
+                "grant_type=refresh_token&refresh_token=unknown",
+                StatusCode::BAD_REQUEST,
+                "invalid_grant",
+            ),
+            (
+                "grant_type=password&username=account%40example.com",
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                "client_id=account%40example.com&client_secret=secret",
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                "grant_type=authorization_code&code=abc",
+                StatusCode::BAD_REQUEST,
+                "unsupported_grant_type",
+            ),
+            (
+                "grant_type=client_credentials&client_id=account%40example.com&client_secret=secret&scope=a%22b",
+                StatusCode::BAD_REQUEST,
+                "invalid_scope",
+            ),
+            (
+                "grant_type=password&username=account%40example.com&password=secret&scope=users:admin",
+                StatusCode::BAD_REQUEST,
+                "invalid_scope",
+            ),
+        ] {
+            assert_eq!(error(form, status).await, code, "{}", form);
+        }
+
+        let response = app
+            .post("/oauth/token")
+            .json(json!({"grant_type": "client_credentials"}))
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        assert_eq!(response.json()["error"], "invalid_request");
+    }
+}
@@ -0,0 +1,181 @@
+//! OpenAPI 3 description of the JSON API, generated from the handler
+//! annotations in `post_service`, and the Swagger UI page that renders it.
+
+use axum::{response::IntoResponse, Json};
+use entity::{audit_log, post_revision, posts};
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::account::{self, ResetConfirmation, ResetRequest, VerificationConfirmation};
+use crate::api_key::{self, ApiKeyInfo, CreatedApiKey, NewApiKey};
+use crate::audit::{self, PaginationAuditLog};
+use crate::comment::{self, CommentInput, PaginationComment};
+use crate::diff::{DiffLine, DiffOp};
+use crate::mfa::{self, MfaChallenge, MfaCompletion, RecoveryCodes, TotpCode, TotpEnrollment};
+use crate::oauth::{self, OAuthErrorBody, TokenRequest, TokenResponse};
+use crate::post_repository::{PaginationPost, TagCount};
+use crate::post_service::{self, AuthBody, AuthPayload, AuthorizeResponse, ErrorBody, FlashData};
+use crate::revision::{self, RevisionDiff};
+use crate::tag;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Axum with SeaORM example app"),
+    paths(
+        post_service::api_list_posts,
+        post_service::api_create_post,
+        post_service::api_update_post,
+        post_service::api_delete_post,
+        revision::list_revisions,
+        revision::get_revision,
+        revision::diff_revisions,
+        revision::rollback_post,
+        tag::list_post_tags,
+        tag::add_tag,
+        tag::remove_tag,
+        tag::list_tags,
+        comment::list_comments,
+        comment::create_comment,
+        comment::update_comment,
+        comment::delete_comment,
+        post_service::authorize_user,
+        mfa::complete_authorization,
+        oauth::token,
+        api_key::create_api_key,
+        api_key::list_api_keys,
+        api_key::revoke_api_key,
+        account::request_password_reset,
+        account::confirm_password_reset,
+        account::request_email_verification,
+        account::confirm_email_verification,
+        mfa::enroll_totp,
+        mfa::confirm_totp,
+        audit::list_audit_log,
+    ),
+    components(schemas(
+        posts::Model,
+        PaginationPost,
+        post_revision::Revision,
+        RevisionDiff,
+        DiffLine,
+        DiffOp,
+        TagCount,
+        entity::comment::Comment,
+        CommentInput,
+        PaginationComment,
+        FlashData,
+        ErrorBody,
+        AuthPayload,
+        AuthBody,
+        AuthorizeResponse,
+        MfaChallenge,
+        MfaCompletion,
+        TokenRequest,
+        TokenResponse,
+        OAuthErrorBody,
+        NewApiKey,
+        ApiKeyInfo,
+        CreatedApiKey,
+        ResetRequest,
+        ResetConfirmation,
+        VerificationConfirmation,
+        TotpEnrollment,
+        TotpCode,
+        RecoveryCodes,
+        audit_log::AuditEntry,
+        PaginationAuditLog,
+    )),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "posts", description = "Blog posts, requires a bearer token"),
+        (name = "revisions", description = "Earlier versions of posts, their diffs and rollback"),
+        (name = "tags", description = "Tags on posts and their usage"),
+        (name = "comments", description = "Discussion of posts"),
+        (name = "auth", description = "Exchange credentials for a bearer token"),
+        (name = "api-keys", description = "Personal API keys, usable as bearer tokens"),
+        (name = "account", description = "Password reset and email verification"),
+        (name = "mfa", description = "Two-factor authentication with TOTP"),
+        (name = "audit", description = "Who changed what, requires the `users:admin` scope"),
+    )
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .build(),
+                ),
+            );
+        }
+    }
+}
+
+// curl http://localhost:8000/openapi.json
+pub async fn openapi_json() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_app::TestApp;
+
+    #[test]
+    fn document_lists_every_route() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = doc["paths"].as_object().unwrap();
+        assert!(paths["/api/"].get("get").is_some());
+        assert!(paths["/api/"].get("post").is_some());
+        assert!(paths["/api/{id}"].get("patch").is_some());
+        assert!(paths["/api/{id}"].get("delete").is_some());
+        assert!(paths["/api/{id}/revisions/{number}/rollback"]
+            .get("post")
+            .is_some());
+        assert!(paths["/authorize"].get("post").is_some());
+        assert!(paths["/oauth/token"].get("post").is_some());
+        assert!(doc["components"]["schemas"]["Post"].is_object());
+        assert!(doc["components"]["securitySchemes"]["bearer"].is_object());
+    }
+
+    #[tokio::test]
+    async fn api_documentation() {
+        let app = TestApp::new().await;
+
+        let response = app
+            .get("/openapi.json")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(response.json()["paths"]["/api/"].is_object());
+
+        let response = app.get("/docs").send().await.assert_status(StatusCode::OK);
+        assert!(response.text().contains("/openapi.json"));
+        for asset in ["swagger-ui.css", "swagger-ui-bundle.js"] {
+            app.get(&format!("/static/swagger-ui/{}", asset))
+                .send()
+                .await
+                .assert_status(StatusCode::OK);
+        }
+
+        // Tokens are signed with `JWT_SECRET`, which is never published
+        let response = app
+            .get("/.well-known/jwks.json")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(response.json(), json!({ "keys": [] }));
+    }
+}
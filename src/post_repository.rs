@@ -0,0 +1,950 @@
+//! Storage of posts behind the `PostRepository` trait.
+//!
+//! The JSON API and the HTML pages only see a `DynPostRepository`, so their
+//! logic can be exercised against `InMemoryPostRepository` without HTTP or a
+//! database; the server uses `SeaOrmPostRepository`.
+//!
+//! Every method takes the tenant of the caller and only ever sees that
+//! tenant's posts: posts of other tenants are reported missing. Changes also
+//! take the email of the caller, for the audit log.
+//!
+//! Each update keeps the version it replaces as a numbered revision, and
+//! deleting a post deletes its revisions and tags.
+
+use std::sync::Arc;
+
+use axum::async_trait;
+use chrono::Utc;
+use entity::post_revision::{self, Entity as PostRevision};
+use entity::posts::{self, Entity as Posts, Model};
+use entity::posts_tags::{self, Entity as PostsTags};
+use entity::tag::{self, Entity as Tag};
+use sea_orm::{
+    prelude::*,
+    sea_query::{Expr, OnConflict, SelectStatement},
+    ConnectionTrait, FromQueryResult, QueryOrder, QuerySelect, QueryTrait, Select, Set,
+    TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::audit;
+
+pub type DynPostRepository = Arc<dyn PostRepository>;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct PaginationPost {
+    #[schema(value_type = Vec<posts::Post>)]
+    posts: Vec<Model>,
+    page: usize,
+    posts_per_page: usize,
+    num_pages: usize,
+}
+
+/// Which tags listed posts must have.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TagFilter {
+    /// Normalized tag names, no filtering if empty
+    pub tags: Vec<String>,
+    /// Whether posts need all of `tags` rather than any
+    pub all: bool,
+}
+
+impl TagFilter {
+    #[cfg(test)]
+    fn matches(&self, post_tags: &[&str]) -> bool {
+        let mut tags = self.tags.iter();
+        if self.tags.is_empty() {
+            true
+        } else if self.all {
+            tags.all(|tag| post_tags.contains(&tag.as_str()))
+        } else {
+            tags.any(|tag| post_tags.contains(&tag.as_str()))
+        }
+    }
+}
+
+/// A tag and the number of posts carrying it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema, FromQueryResult)]
+pub struct TagCount {
+    pub name: String,
+    pub posts: i64,
+}
+
+#[async_trait]
+pub trait PostRepository: Send + Sync {
+    /// One 1-based page of the posts matching `tags`, ordered by id.
+    async fn list(
+        &self,
+        tenant: i32,
+        tags: &TagFilter,
+        page: usize,
+        posts_per_page: usize,
+    ) -> Result<PaginationPost, DbErr>;
+
+    async fn get(&self, tenant: i32, id: i32) -> Result<Option<Model>, DbErr>;
+
+    /// Insert `input` for `tenant`, ignoring its id, and return the stored post.
+    async fn create(&self, tenant: i32, actor: &str, input: Model) -> Result<Model, DbErr>;
+
+    /// Replace the post `id` with `input`; `DbErr::RecordNotFound` if missing.
+    async fn update(&self, tenant: i32, actor: &str, id: i32, input: Model)
+        -> Result<Model, DbErr>;
+
+    /// Delete the post `id`; `DbErr::RecordNotFound` if missing.
+    async fn delete(&self, tenant: i32, actor: &str, id: i32) -> Result<(), DbErr>;
+
+    /// Earlier versions of the post `id`, oldest first;
+    /// `DbErr::RecordNotFound` if the post is missing.
+    async fn revisions(&self, tenant: i32, id: i32) -> Result<Vec<post_revision::Model>, DbErr>;
+
+    /// Revision `number` of the post `id`; `DbErr::RecordNotFound` if the
+    /// post is missing.
+    async fn revision(
+        &self,
+        tenant: i32,
+        id: i32,
+        number: i32,
+    ) -> Result<Option<post_revision::Model>, DbErr>;
+
+    /// Names of the tags of the post `id`, sorted; `DbErr::RecordNotFound` if
+    /// the post is missing.
+    async fn tags(&self, tenant: i32, id: i32) -> Result<Vec<String>, DbErr>;
+
+    /// Tag the post `id` with the normalized `name`, creating the tag if new;
+    /// `DbErr::RecordNotFound` if the post is missing. Tagging twice does
+    /// nothing.
+    async fn add_tag(&self, tenant: i32, id: i32, name: &str) -> Result<(), DbErr>;
+
+    /// Remove the tag `name` from the post `id` and tell whether it had it;
+    /// `DbErr::RecordNotFound` if the post is missing.
+    async fn remove_tag(&self, tenant: i32, id: i32, name: &str) -> Result<bool, DbErr>;
+
+    /// Tags on at least one post, by name, with their number of posts.
+    async fn tag_counts(&self, tenant: i32) -> Result<Vec<TagCount>, DbErr>;
+}
+
+fn not_found(id: i32) -> DbErr {
+    DbErr::RecordNotFound(format!("post {}", id))
+}
+
+async fn find<C: ConnectionTrait>(conn: &C, tenant: i32, id: i32) -> Result<Option<Model>, DbErr> {
+    Posts::find_by_id(id)
+        .filter(posts::Column::TenantId.eq(tenant))
+        .one(conn)
+        .await
+}
+
+/// Ids of the posts of `tenant` tagged with any of `names`.
+fn tagged_with(tenant: i32, names: &[String]) -> SelectStatement {
+    PostsTags::find()
+        .select_only()
+        .column(posts_tags::Column::PostId)
+        .inner_join(Tag)
+        .filter(tag::Column::TenantId.eq(tenant))
+        .filter(tag::Column::Name.is_in(names.iter().map(String::as_str)))
+        .into_query()
+}
+
+fn filter_tags(mut select: Select<Posts>, tenant: i32, filter: &TagFilter) -> Select<Posts> {
+    if filter.all {
+        for name in &filter.tags {
+            let tagged = tagged_with(tenant, std::slice::from_ref(name));
+            select = select.filter(posts::Column::Id.in_subquery(tagged));
+        }
+    } else if !filter.tags.is_empty() {
+        select = select.filter(posts::Column::Id.in_subquery(tagged_with(tenant, &filter.tags)));
+    }
+    select
+}
+
+async fn find_tag<C: ConnectionTrait>(
+    conn: &C,
+    tenant: i32,
+    name: &str,
+) -> Result<Option<tag::Model>, DbErr> {
+    Tag::find()
+        .filter(tag::Column::TenantId.eq(tenant))
+        .filter(tag::Column::Name.eq(name))
+        .one(conn)
+        .await
+}
+
+pub struct SeaOrmPostRepository {
+    conn: DatabaseConnection,
+}
+
+impl SeaOrmPostRepository {
+    pub fn new(conn: DatabaseConnection) -> Self {
+        Self { conn }
+    }
+}
+
+#[async_trait]
+impl PostRepository for SeaOrmPostRepository {
+    async fn list(
+        &self,
+        tenant: i32,
+        tags: &TagFilter,
+        page: usize,
+        posts_per_page: usize,
+    ) -> Result<PaginationPost, DbErr> {
+        let select = Posts::find().filter(posts::Column::TenantId.eq(tenant));
+        let paginator = filter_tags(select, tenant, tags)
+            .order_by_asc(posts::Column::Id)
+            .paginate(&self.conn, posts_per_page);
+        let num_pages = paginator.num_pages().await?;
+        let posts = paginator.fetch_page(page - 1).await?;
+
+        Ok(PaginationPost {
+            posts,
+            page,
+            posts_per_page,
+            num_pages,
+        })
+    }
+
+    async fn get(&self, tenant: i32, id: i32) -> Result<Option<Model>, DbErr> {
+        find(&self.conn, tenant, id).await
+    }
+
+    async fn create(&self, tenant: i32, actor: &str, input: Model) -> Result<Model, DbErr> {
+        let txn = self.conn.begin().await?;
+        let post = posts::ActiveModel {
+            title: Set(input.title),
+            text: Set(input.text),
+            new_col: Set(input.new_col),
+            tenant_id: Set(tenant),
+            ..Default::default()
+        }
+        .insert(&txn)
+        .await?;
+        audit::created(&txn, Some(actor), &post).await?;
+        txn.commit().await?;
+
+        Ok(post)
+    }
+
+    async fn update(
+        &self,
+        tenant: i32,
+        actor: &str,
+        id: i32,
+        input: Model,
+    ) -> Result<Model, DbErr> {
+        let txn = self.conn.begin().await?;
+        // Updates go by primary key only, so check the tenant first; posts
+        // never change tenant. The row lock makes concurrent updates of the
+        // post take revision numbers in turn instead of colliding on the
+        // unique (post_id, number) index.
+        let before = Posts::find_by_id(id)
+            .filter(posts::Column::TenantId.eq(tenant))
+            .lock_exclusive()
+            .one(&txn)
+            .await?
+            .ok_or_else(|| not_found(id))?;
+        let number = PostRevision::find()
+            .filter(post_revision::Column::PostId.eq(id))
+            .order_by_desc(post_revision::Column::Number)
+            .one(&txn)
+            .await?
+            .map_or(1, |revision| revision.number + 1);
+        post_revision::ActiveModel {
+            post_id: Set(id),
+            number: Set(number),
+            title: Set(before.title.clone()),
+            text: Set(before.text.clone()),
+            new_col: Set(before.new_col),
+            replaced_by: Set(actor.to_owned()),
+            replaced_at: Set(Utc::now()),
+            ..Default::default()
+        }
+        .insert(&txn)
+        .await?;
+        let post = posts::ActiveModel {
+            id: Set(id),
+            title: Set(input.title),
+            text: Set(input.text),
+            new_col: Set(input.new_col),
+            tenant_id: Set(tenant),
+        }
+        .update(&txn)
+        .await?;
+        audit::updated(&txn, Some(actor), &before, &post).await?;
+        txn.commit().await?;
+
+        Ok(post)
+    }
+
+    async fn delete(&self, tenant: i32, actor: &str, id: i32) -> Result<(), DbErr> {
+        let txn = self.conn.begin().await?;
+        let before = find(&txn, tenant, id).await?.ok_or_else(|| not_found(id))?;
+        Posts::delete_by_id(id).exec(&txn).await?;
+        audit::deleted(&txn, Some(actor), &before).await?;
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    async fn revisions(&self, tenant: i32, id: i32) -> Result<Vec<post_revision::Model>, DbErr> {
+        let post = self.get(tenant, id).await?.ok_or_else(|| not_found(id))?;
+        post.find_related(PostRevision)
+            .order_by_asc(post_revision::Column::Number)
+            .all(&self.conn)
+            .await
+    }
+
+    async fn revision(
+        &self,
+        tenant: i32,
+        id: i32,
+        number: i32,
+    ) -> Result<Option<post_revision::Model>, DbErr> {
+        let post = self.get(tenant, id).await?.ok_or_else(|| not_found(id))?;
+        post.find_related(PostRevision)
+            .filter(post_revision::Column::Number.eq(number))
+            .one(&self.conn)
+            .await
+    }
+
+    async fn tags(&self, tenant: i32, id: i32) -> Result<Vec<String>, DbErr> {
+        let post = self.get(tenant, id).await?.ok_or_else(|| not_found(id))?;
+        let tags = post
+            .find_related(Tag)
+            .order_by_asc(tag::Column::Name)
+            .all(&self.conn)
+            .await?;
+
+        Ok(tags.into_iter().map(|tag| tag.name).collect())
+    }
+
+    async fn add_tag(&self, tenant: i32, id: i32, name: &str) -> Result<(), DbErr> {
+        let txn = self.conn.begin().await?;
+        find(&txn, tenant, id).await?.ok_or_else(|| not_found(id))?;
+        // Concurrent requests may add the same tag: the inserts turn into no-op
+        // updates on conflict (`do_nothing` has no MySQL form) instead of
+        // failing on the unique indexes
+        let backend = txn.get_database_backend();
+        let insert_tag = Tag::insert(tag::ActiveModel {
+            tenant_id: Set(tenant),
+            name: Set(name.to_owned()),
+            ..Default::default()
+        })
+        .on_conflict(
+            OnConflict::columns([tag::Column::TenantId, tag::Column::Name])
+                .update_column(tag::Column::Name)
+                .to_owned(),
+        )
+        .build(backend);
+        txn.execute(insert_tag).await?;
+        let tag = find_tag(&txn, tenant, name)
+            .await?
+            .ok_or_else(|| DbErr::RecordNotFound(format!("tag {}", name)))?;
+        let insert_post_tag = PostsTags::insert(posts_tags::ActiveModel {
+            post_id: Set(id),
+            tag_id: Set(tag.id),
+        })
+        .on_conflict(
+            OnConflict::columns([posts_tags::Column::PostId, posts_tags::Column::TagId])
+                .update_column(posts_tags::Column::PostId)
+                .to_owned(),
+        )
+        .build(backend);
+        txn.execute(insert_post_tag).await?;
+        txn.commit().await
+    }
+
+    async fn remove_tag(&self, tenant: i32, id: i32, name: &str) -> Result<bool, DbErr> {
+        self.get(tenant, id).await?.ok_or_else(|| not_found(id))?;
+        let tag = match find_tag(&self.conn, tenant, name).await? {
+            Some(tag) => tag,
+            None => return Ok(false),
+        };
+        let deleted = PostsTags::delete_many()
+            .filter(posts_tags::Column::PostId.eq(id))
+            .filter(posts_tags::Column::TagId.eq(tag.id))
+            .exec(&self.conn)
+            .await?;
+
+        Ok(deleted.rows_affected > 0)
+    }
+
+    async fn tag_counts(&self, tenant: i32) -> Result<Vec<TagCount>, DbErr> {
+        Tag::find()
+            .select_only()
+            .column(tag::Column::Name)
+            .column_as(
+                Expr::tbl(PostsTags, posts_tags::Column::PostId).count(),
+                "posts",
+            )
+            .inner_join(PostsTags)
+            .filter(tag::Column::TenantId.eq(tenant))
+            .group_by(tag::Column::Name)
+            .order_by_asc(tag::Column::Name)
+            .into_model::<TagCount>()
+            .all(&self.conn)
+            .await
+    }
+}
+
+/// Posts kept in a `Vec`, for unit tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct InMemoryPostRepository {
+    posts: std::sync::Mutex<Vec<Model>>,
+    revisions: std::sync::Mutex<Vec<post_revision::Model>>,
+    /// Post id and tag name
+    tags: std::sync::Mutex<Vec<(i32, String)>>,
+}
+
+#[cfg(test)]
+impl InMemoryPostRepository {
+    fn tags_of(&self, id: i32) -> Vec<String> {
+        let mut tags: Vec<String> = self
+            .tags
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(post_id, _)| *post_id == id)
+            .map(|(_, name)| name.clone())
+            .collect();
+        tags.sort();
+        tags
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl PostRepository for InMemoryPostRepository {
+    async fn list(
+        &self,
+        tenant: i32,
+        tags: &TagFilter,
+        page: usize,
+        posts_per_page: usize,
+    ) -> Result<PaginationPost, DbErr> {
+        let posts = self.posts.lock().unwrap();
+        let posts: Vec<&Model> = posts
+            .iter()
+            .filter(|post| post.tenant_id == tenant)
+            .filter(|post| {
+                let post_tags = self.tags_of(post.id);
+                let post_tags: Vec<&str> = post_tags.iter().map(String::as_str).collect();
+                tags.matches(&post_tags)
+            })
+            .collect();
+        let num_pages = posts.len().div_ceil(posts_per_page);
+        let posts = posts
+            .into_iter()
+            .skip((page - 1) * posts_per_page)
+            .take(posts_per_page)
+            .cloned()
+            .collect();
+
+        Ok(PaginationPost {
+            posts,
+            page,
+            posts_per_page,
+            num_pages,
+        })
+    }
+
+    async fn get(&self, tenant: i32, id: i32) -> Result<Option<Model>, DbErr> {
+        let posts = self.posts.lock().unwrap();
+        Ok(posts
+            .iter()
+            .find(|post| post.id == id && post.tenant_id == tenant)
+            .cloned())
+    }
+
+    async fn create(&self, tenant: i32, _actor: &str, input: Model) -> Result<Model, DbErr> {
+        let mut posts = self.posts.lock().unwrap();
+        let post = Model {
+            id: posts.last().map_or(1, |post| post.id + 1),
+            tenant_id: tenant,
+            ..input
+        };
+        posts.push(post.clone());
+
+        Ok(post)
+    }
+
+    async fn update(
+        &self,
+        tenant: i32,
+        actor: &str,
+        id: i32,
+        input: Model,
+    ) -> Result<Model, DbErr> {
+        let mut posts = self.posts.lock().unwrap();
+        let post = posts
+            .iter_mut()
+            .find(|post| post.id == id && post.tenant_id == tenant)
+            .ok_or_else(|| not_found(id))?;
+        let mut revisions = self.revisions.lock().unwrap();
+        let number = revisions
+            .iter()
+            .filter(|revision| revision.post_id == id)
+            .map(|revision| revision.number + 1)
+            .max()
+            .unwrap_or(1);
+        let revision_id = revisions.len() as i32 + 1;
+        revisions.push(post_revision::Model {
+            id: revision_id,
+            post_id: id,
+            number,
+            title: post.title.clone(),
+            text: post.text.clone(),
+            new_col: post.new_col,
+            replaced_by: actor.to_owned(),
+            replaced_at: Utc::now(),
+        });
+        *post = Model {
+            id,
+            tenant_id: tenant,
+            ..input
+        };
+
+        Ok(post.clone())
+    }
+
+    async fn delete(&self, tenant: i32, _actor: &str, id: i32) -> Result<(), DbErr> {
+        let mut posts = self.posts.lock().unwrap();
+        let index = posts
+            .iter()
+            .position(|post| post.id == id && post.tenant_id == tenant)
+            .ok_or_else(|| not_found(id))?;
+        posts.remove(index);
+        self.revisions
+            .lock()
+            .unwrap()
+            .retain(|revision| revision.post_id != id);
+        self.tags
+            .lock()
+            .unwrap()
+            .retain(|(post_id, _)| *post_id != id);
+
+        Ok(())
+    }
+
+    async fn revisions(&self, tenant: i32, id: i32) -> Result<Vec<post_revision::Model>, DbErr> {
+        self.get(tenant, id).await?.ok_or_else(|| not_found(id))?;
+        let mut revisions: Vec<_> = self
+            .revisions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|revision| revision.post_id == id)
+            .cloned()
+            .collect();
+        revisions.sort_by_key(|revision| revision.number);
+
+        Ok(revisions)
+    }
+
+    async fn revision(
+        &self,
+        tenant: i32,
+        id: i32,
+        number: i32,
+    ) -> Result<Option<post_revision::Model>, DbErr> {
+        self.get(tenant, id).await?.ok_or_else(|| not_found(id))?;
+        Ok(self
+            .revisions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|revision| revision.post_id == id && revision.number == number)
+            .cloned())
+    }
+
+    async fn tags(&self, tenant: i32, id: i32) -> Result<Vec<String>, DbErr> {
+        self.get(tenant, id).await?.ok_or_else(|| not_found(id))?;
+        Ok(self.tags_of(id))
+    }
+
+    async fn add_tag(&self, tenant: i32, id: i32, name: &str) -> Result<(), DbErr> {
+        self.get(tenant, id).await?.ok_or_else(|| not_found(id))?;
+        let mut tags = self.tags.lock().unwrap();
+        if !tags
+            .iter()
+            .any(|(post_id, tag)| *post_id == id && tag == name)
+        {
+            tags.push((id, name.to_owned()));
+        }
+
+        Ok(())
+    }
+
+    async fn remove_tag(&self, tenant: i32, id: i32, name: &str) -> Result<bool, DbErr> {
+        self.get(tenant, id).await?.ok_or_else(|| not_found(id))?;
+        let mut tags = self.tags.lock().unwrap();
+        let before = tags.len();
+        tags.retain(|(post_id, tag)| !(*post_id == id && tag == name));
+
+        Ok(tags.len() < before)
+    }
+
+    async fn tag_counts(&self, tenant: i32) -> Result<Vec<TagCount>, DbErr> {
+        let posts = self.posts.lock().unwrap();
+        let mut counts = std::collections::BTreeMap::new();
+        for (post_id, name) in self.tags.lock().unwrap().iter() {
+            if posts
+                .iter()
+                .any(|post| post.id == *post_id && post.tenant_id == tenant)
+            {
+                *counts.entry(name.clone()).or_insert(0) += 1;
+            }
+        }
+
+        Ok(counts
+            .into_iter()
+            .map(|(name, posts)| TagCount { name, posts })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use migration::{Migrator, MigratorTrait};
+    use sea_orm::Database;
+
+    const TENANT: i32 = 1;
+    const OTHER_TENANT: i32 = 2;
+    const ACTOR: &str = "account@example.com";
+
+    fn post(title: &str) -> Model {
+        Model {
+            id: 0,
+            title: title.to_owned(),
+            text: format!("{} text", title),
+            new_col: 17,
+            tenant_id: 0,
+        }
+    }
+
+    /// Both implementations have to behave the same.
+    async fn check(repo: &dyn PostRepository) {
+        let page = repo
+            .list(TENANT, &TagFilter::default(), 1, 5)
+            .await
+            .unwrap();
+        assert!(page.posts.is_empty());
+        assert_eq!(page.num_pages, 0);
+
+        let first = repo.create(TENANT, ACTOR, post("first")).await.unwrap();
+        let second = repo.create(TENANT, ACTOR, post("second")).await.unwrap();
+        let third = repo.create(TENANT, ACTOR, post("third")).await.unwrap();
+        assert_eq!((first.id, second.id, third.id), (1, 2, 3));
+        assert_eq!(first.tenant_id, TENANT);
+        assert_eq!(repo.get(TENANT, 2).await.unwrap(), Some(second));
+        assert_eq!(repo.get(TENANT, 42).await.unwrap(), None);
+
+        let page = repo
+            .list(TENANT, &TagFilter::default(), 2, 2)
+            .await
+            .unwrap();
+        assert_eq!(page.num_pages, 2);
+        assert_eq!(page.posts, vec![third]);
+
+        let updated = repo
+            .update(TENANT, ACTOR, 1, post("updated"))
+            .await
+            .unwrap();
+        assert_eq!((updated.id, updated.tenant_id), (1, TENANT));
+        assert_eq!(repo.get(TENANT, 1).await.unwrap(), Some(updated));
+        assert!(matches!(
+            repo.update(TENANT, ACTOR, 42, post("missing")).await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+
+        repo.update(TENANT, ACTOR, 1, post("again")).await.unwrap();
+        let revisions = repo.revisions(TENANT, 1).await.unwrap();
+        let versions: Vec<_> = revisions
+            .iter()
+            .map(|revision| (revision.number, revision.title.as_str()))
+            .collect();
+        assert_eq!(versions, vec![(1, "first"), (2, "updated")]);
+        assert_eq!(revisions[0].text, "first text");
+        assert_eq!(revisions[0].replaced_by, ACTOR);
+        assert_eq!(
+            repo.revision(TENANT, 1, 2).await.unwrap().as_ref(),
+            Some(&revisions[1])
+        );
+        assert_eq!(repo.revision(TENANT, 1, 3).await.unwrap(), None);
+        assert!(repo.revisions(TENANT, 2).await.unwrap().is_empty());
+        assert!(matches!(
+            repo.revisions(TENANT, 42).await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+
+        repo.delete(TENANT, ACTOR, 1).await.unwrap();
+        assert_eq!(repo.get(TENANT, 1).await.unwrap(), None);
+        assert!(matches!(
+            repo.revisions(TENANT, 1).await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+        assert!(matches!(
+            repo.delete(TENANT, ACTOR, 1).await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+    }
+
+    async fn check_tags(repo: &dyn PostRepository) {
+        for title in ["first", "second", "third"] {
+            repo.create(TENANT, ACTOR, post(title)).await.unwrap();
+        }
+        repo.add_tag(TENANT, 1, "rust").await.unwrap();
+        repo.add_tag(TENANT, 1, "axum").await.unwrap();
+        // Tagging twice does nothing
+        repo.add_tag(TENANT, 1, "axum").await.unwrap();
+        repo.add_tag(TENANT, 2, "rust").await.unwrap();
+        repo.add_tag(TENANT, 3, "web").await.unwrap();
+        assert_eq!(repo.tags(TENANT, 1).await.unwrap(), ["axum", "rust"]);
+        assert!(matches!(
+            repo.add_tag(TENANT, 42, "rust").await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+
+        let titles = |tags: &[&str], all: bool| {
+            let filter = TagFilter {
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                all,
+            };
+            async move {
+                let page = repo.list(TENANT, &filter, 1, 5).await.unwrap();
+                page.posts
+                    .into_iter()
+                    .map(|post| post.title)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(titles(&["rust"], false).await, ["first", "second"]);
+        assert_eq!(titles(&["axum", "web"], false).await, ["first", "third"]);
+        assert_eq!(titles(&["axum", "rust"], true).await, ["first"]);
+        assert!(titles(&["axum", "web"], true).await.is_empty());
+        assert!(titles(&["missing"], false).await.is_empty());
+        assert_eq!(titles(&[], true).await.len(), 3);
+
+        let count = |name: &str, posts| TagCount {
+            name: name.to_owned(),
+            posts,
+        };
+        assert_eq!(
+            repo.tag_counts(TENANT).await.unwrap(),
+            [count("axum", 1), count("rust", 2), count("web", 1)]
+        );
+
+        assert!(repo.remove_tag(TENANT, 1, "rust").await.unwrap());
+        assert!(!repo.remove_tag(TENANT, 1, "rust").await.unwrap());
+        assert!(!repo.remove_tag(TENANT, 1, "missing").await.unwrap());
+        assert_eq!(titles(&["rust"], false).await, ["second"]);
+        repo.delete(TENANT, ACTOR, 3).await.unwrap();
+        assert_eq!(
+            repo.tag_counts(TENANT).await.unwrap(),
+            [count("axum", 1), count("rust", 1)]
+        );
+    }
+
+    /// Another tenant can neither see nor touch the posts of the first.
+    async fn check_isolation(repo: &dyn PostRepository) {
+        let mine = repo.create(TENANT, ACTOR, post("mine")).await.unwrap();
+        let theirs = repo
+            .create(OTHER_TENANT, ACTOR, post("theirs"))
+            .await
+            .unwrap();
+
+        let page = repo
+            .list(OTHER_TENANT, &TagFilter::default(), 1, 5)
+            .await
+            .unwrap();
+        assert_eq!(page.posts, vec![theirs.clone()]);
+        assert_eq!(page.num_pages, 1);
+        assert_eq!(repo.get(OTHER_TENANT, mine.id).await.unwrap(), None);
+        assert!(matches!(
+            repo.update(OTHER_TENANT, ACTOR, mine.id, post("stolen"))
+                .await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+        assert!(matches!(
+            repo.delete(OTHER_TENANT, ACTOR, mine.id).await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+        assert!(matches!(
+            repo.revisions(OTHER_TENANT, mine.id).await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+        assert!(matches!(
+            repo.revision(OTHER_TENANT, mine.id, 1).await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+        assert!(matches!(
+            repo.add_tag(OTHER_TENANT, mine.id, "stolen").await,
+            Err(DbErr::RecordNotFound(_))
+        ));
+        // Tags with the same name stay apart
+        repo.add_tag(TENANT, mine.id, "shared").await.unwrap();
+        repo.add_tag(OTHER_TENANT, theirs.id, "shared")
+            .await
+            .unwrap();
+        let shared = TagFilter {
+            tags: vec!["shared".to_owned()],
+            all: false,
+        };
+        let page = repo.list(OTHER_TENANT, &shared, 1, 5).await.unwrap();
+        assert_eq!(page.posts, vec![theirs.clone()]);
+        assert_eq!(
+            repo.tag_counts(OTHER_TENANT).await.unwrap(),
+            [TagCount {
+                name: "shared".to_owned(),
+                posts: 1
+            }]
+        );
+
+        assert_eq!(repo.get(TENANT, mine.id).await.unwrap(), Some(mine));
+        assert_eq!(
+            repo.list(TENANT, &TagFilter::default(), 1, 5)
+                .await
+                .unwrap()
+                .posts
+                .len(),
+            1
+        );
+        assert_eq!(repo.get(TENANT, theirs.id).await.unwrap(), None);
+    }
+
+    async fn sea_orm_conn() -> sea_orm::DatabaseConnection {
+        let conn = Database::connect("sqlite::memory:".to_string())
+            .await
+            .expect("Database connection failed");
+        Migrator::up(&conn, None).await.unwrap();
+        // Posts and tags reference their tenant
+        entity::tenant::ActiveModel {
+            id: Set(OTHER_TENANT),
+            name: Set("Globex".to_owned()),
+        }
+        .insert(&conn)
+        .await
+        .unwrap();
+        conn
+    }
+
+    #[tokio::test]
+    async fn concurrent_updates_number_revisions_in_turn() {
+        let repo = Arc::new(SeaOrmPostRepository::new(sea_orm_conn().await));
+        let created = repo.create(TENANT, ACTOR, post("first")).await.unwrap();
+
+        let updates: Vec<_> = (0..5)
+            .map(|i| {
+                let repo = repo.clone();
+                tokio::spawn(async move {
+                    repo.update(TENANT, ACTOR, created.id, post(&format!("update {}", i)))
+                        .await
+                })
+            })
+            .collect();
+        for update in updates {
+            update.await.unwrap().unwrap();
+        }
+
+        let numbers: Vec<_> = repo
+            .revisions(TENANT, created.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|revision| revision.number)
+            .collect();
+        assert_eq!(numbers, [1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_tagging_does_not_conflict() {
+        let repo = Arc::new(SeaOrmPostRepository::new(sea_orm_conn().await));
+        let first = repo.create(TENANT, ACTOR, post("first")).await.unwrap();
+        let second = repo.create(TENANT, ACTOR, post("second")).await.unwrap();
+
+        let tagging: Vec<_> = [first.id, first.id, second.id, second.id]
+            .into_iter()
+            .map(|id| {
+                let repo = repo.clone();
+                tokio::spawn(async move { repo.add_tag(TENANT, id, "rust").await })
+            })
+            .collect();
+        for tag in tagging {
+            tag.await.unwrap().unwrap();
+        }
+
+        assert_eq!(
+            repo.tag_counts(TENANT).await.unwrap(),
+            [TagCount {
+                name: "rust".to_owned(),
+                posts: 2
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn sea_orm_repository_audits_changes() {
+        use entity::audit_log::{self, Entity as AuditLog};
+
+        let conn = sea_orm_conn().await;
+        let repo = SeaOrmPostRepository::new(conn.clone());
+        let created = repo.create(TENANT, ACTOR, post("first")).await.unwrap();
+        let updated = repo
+            .update(TENANT, ACTOR, created.id, post("updated"))
+            .await
+            .unwrap();
+        repo.delete(TENANT, ACTOR, created.id).await.unwrap();
+        // Failed changes leave no trace
+        repo.delete(TENANT, ACTOR, created.id).await.unwrap_err();
+        // The revisions went with the post
+        assert!(PostRevision::find().all(&conn).await.unwrap().is_empty());
+
+        let entries = AuditLog::find()
+            .order_by_asc(audit_log::Column::Id)
+            .all(&conn)
+            .await
+            .unwrap();
+        let changes: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.action.as_str(),
+                    entry.before.clone(),
+                    entry.after.clone(),
+                )
+            })
+            .collect();
+        let created = serde_json::to_value(&created).unwrap();
+        let updated = serde_json::to_value(&updated).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                ("create", None, Some(created.clone())),
+                ("update", Some(created), Some(updated.clone())),
+                ("delete", Some(updated), None),
+            ]
+        );
+        for entry in entries {
+            assert_eq!(entry.actor.as_deref(), Some(ACTOR));
+            assert_eq!(entry.tenant_id, Some(TENANT));
+            assert_eq!((entry.entity_type.as_str(), entry.entity_id), ("posts", 1));
+        }
+    }
+
+    #[tokio::test]
+    async fn in_memory_repository() {
+        check(&InMemoryPostRepository::default()).await;
+        check_tags(&InMemoryPostRepository::default()).await;
+        check_isolation(&InMemoryPostRepository::default()).await;
+    }
+
+    #[tokio::test]
+    async fn sea_orm_repository() {
+        check(&SeaOrmPostRepository::new(sea_orm_conn().await)).await;
+        check_tags(&SeaOrmPostRepository::new(sea_orm_conn().await)).await;
+        check_isolation(&SeaOrmPostRepository::new(sea_orm_conn().await)).await;
+    }
+}
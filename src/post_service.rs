@@ -2,247 +2,845 @@ use lazy_static::lazy_static;
 use ring::hmac;
 use ring::hmac::Key;
 use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::api_key;
+use crate::keys::{JwkSet, KeyRing};
+use crate::metrics::{self, AuthSource};
+use crate::mfa::{self, MfaChallenge};
+use crate::oauth::TokenConfig;
+use crate::post_repository::{DynPostRepository, PaginationPost, TagFilter};
+use crate::rate_limit::{self, RateLimiter};
+use crate::scope::{self, PostsRead, PostsWrite, RequireScope, Scope};
+use crate::session;
+use crate::tag;
 use axum::{
     async_trait,
-    extract::{Extension, FromRequest, Path, Query, RequestParts, TypedHeader},
+    extract::{ConnectInfo, Extension, FromRequest, Path, Query, RequestParts, TypedHeader},
     headers::{authorization::Bearer, Authorization},
+    http::header,
     response::{IntoResponse, Response},
     Json,
 };
-use entity::posts::{self, Model};
+use entity::posts;
 use serde_json::json;
 
 use entity::user;
 use hyper::StatusCode;
-use posts::Entity as Posts;
-use sea_orm::{prelude::*, QueryOrder, Set};
+use sea_orm::prelude::*;
 use serde::{Deserialize, Serialize};
 use user::Entity as User;
+use utoipa::{IntoParams, ToSchema};
 
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::errors::ErrorKind;
+use tower_cookies::Cookies;
 
 lazy_static! {
     static ref SECRET: String = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
     static ref KEY: Key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
-    static ref KEYS: Keys = Keys::new(SECRET.as_bytes());
+    static ref KEYS: KeyRing = KeyRing::from_env(SECRET.as_bytes());
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct Params {
+    /// 1-based page number, defaults to 1
     page: Option<usize>,
+    /// Page size, defaults to 5, at least 1
     posts_per_page: Option<usize>,
+    /// Comma-separated tag names, to list only posts with any of them
+    tags: Option<String>,
+    /// Whether posts need `any` (default) or `all` of `tags`
+    #[serde(rename = "match")]
+    #[param(inline)]
+    tag_match: Option<TagMatch>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct PaginationPost {
-    posts: Vec<Model>,
-    page: usize,
-    posts_per_page: usize,
-    num_pages: usize,
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TagMatch {
+    Any,
+    All,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, ToSchema)]
 pub struct FlashData {
     kind: String,
     message: String,
 }
 
-// curl http://localhost:8000/api/?page\=1&posts_per_page=100
+/// Body of every error response
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    error: String,
+}
+
+// curl http://localhost:8000/api/?page\=1&posts_per_page=100&tags=rust,axum&match=all
+#[utoipa::path(
+    get,
+    path = "/api/",
+    tag = "posts",
+    params(Params),
+    responses(
+        (status = 200, description = "One page of posts ordered by id, with the given tags", body = PaginationPost),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:read` scope", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:read"]))
+)]
 pub async fn api_list_posts(
-    claims: Claims,
-    Extension(ref conn): Extension<DatabaseConnection>,
+    RequireScope(claims, _): RequireScope<PostsRead>,
+    Extension(repo): Extension<DynPostRepository>,
     Query(params): Query<Params>,
-) -> impl IntoResponse {
-    tracing::info!("claims: {:?}", claims);
-    let page = params.page.unwrap_or(1);
-    let posts_per_page = params.posts_per_page.unwrap_or(5);
-    let paginator = Posts::find()
-        .order_by_asc(posts::Column::Id)
-        .paginate(conn, posts_per_page);
-    let num_pages = paginator.num_pages().await.ok().unwrap();
-    let posts = paginator
-        .fetch_page(page - 1)
-        .await
-        .expect("could not retrieve posts");
+) -> Result<Json<PaginationPost>, PostError> {
+    tracing::info!("listing posts");
+    let page = repo
+        .list(
+            claims.tenant(),
+            &params.tag_filter(),
+            params.page(),
+            params.posts_per_page(),
+        )
+        .await?;
 
-    let page = PaginationPost {
-        posts,
-        page,
-        posts_per_page,
-        num_pages,
-    };
-
-    Json(page)
+    Ok(Json(page))
 }
 
 // curl -X POST -H 'Content-Type: application/json' http://localhost:8000/api/ --data '{"title": "title11", "text":"text11","new_col":0}'
+#[utoipa::path(
+    post,
+    path = "/api/",
+    tag = "posts",
+    request_body = posts::Post,
+    responses(
+        (status = 200, description = "Post created", body = FlashData),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:write` scope", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:write"]))
+)]
 pub async fn api_create_post(
-    claims: Claims,
-    Extension(ref conn): Extension<DatabaseConnection>,
+    RequireScope(claims, _): RequireScope<PostsWrite>,
+    Extension(repo): Extension<DynPostRepository>,
     Json(input): Json<posts::Model>,
-) -> impl IntoResponse {
-    tracing::info!("claims: {:?}", claims);
-    posts::ActiveModel {
-        title: Set(input.title.to_owned()),
-        text: Set(input.text.to_owned()),
-        new_col: Set(input.new_col.to_owned()),
-        ..Default::default()
-    }
-    .save(conn)
-    .await
-    .expect("could not insert post");
-
-    let data = FlashData {
-        kind: "success".to_owned(),
-        message: "Post succcessfully added".to_owned(),
-    };
+) -> Result<Json<FlashData>, PostError> {
+    tracing::info!("creating post");
+    repo.create(claims.tenant(), claims.sub(), input).await?;
 
-    Json(data)
+    Ok(Json(FlashData::success("Post succcessfully added")))
 }
 
 // curl -X PATCH -H 'Content-Type: application/json' http://localhost:8000/api/12 --data '{"title": "title11", "text":"text11","new_col":4}'
+#[utoipa::path(
+    patch,
+    path = "/api/{id}",
+    tag = "posts",
+    params(("id" = i32, Path, description = "Post id")),
+    request_body = posts::Post,
+    responses(
+        (status = 200, description = "Post updated", body = FlashData),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:write` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:write"]))
+)]
 pub async fn api_update_post(
-    claims: Claims,
-    Extension(ref conn): Extension<DatabaseConnection>,
+    RequireScope(claims, _): RequireScope<PostsWrite>,
+    Extension(repo): Extension<DynPostRepository>,
     Path(id): Path<i32>,
     Json(input): Json<posts::Model>,
-) -> impl IntoResponse {
-    tracing::info!("claims: {:?}", claims);
-    posts::ActiveModel {
-        id: Set(id),
-        title: Set(input.title.to_owned()),
-        text: Set(input.text.to_owned()),
-        new_col: Set(input.new_col.to_owned()),
-    }
-    .save(conn)
-    .await
-    .expect("could not edit post");
-
-    let data = FlashData {
-        kind: "success".to_owned(),
-        message: "Post succcessfully updated".to_owned(),
-    };
+) -> Result<Json<FlashData>, PostError> {
+    tracing::info!(id, "updating post");
+    repo.update(claims.tenant(), claims.sub(), id, input)
+        .await?;
 
-    Json(data)
+    Ok(Json(FlashData::success("Post succcessfully updated")))
 }
 
 // curl -X DELETE  http://localhost:8000/api/12
+#[utoipa::path(
+    delete,
+    path = "/api/{id}",
+    tag = "posts",
+    params(("id" = i32, Path, description = "Post id")),
+    responses(
+        (status = 200, description = "Post deleted", body = FlashData),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:write` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:write"]))
+)]
 pub async fn api_delete_post(
-    claims: Claims,
-    Extension(ref conn): Extension<DatabaseConnection>,
+    RequireScope(claims, _): RequireScope<PostsWrite>,
+    Extension(repo): Extension<DynPostRepository>,
     Path(id): Path<i32>,
-) -> impl IntoResponse {
-    tracing::info!("claims: {:?}", claims);
-    let post: posts::ActiveModel = Posts::find_by_id(id)
-        .one(conn)
-        .await
-        .unwrap()
-        .unwrap()
-        .into();
+) -> Result<Json<FlashData>, PostError> {
+    tracing::info!(id, "deleting post");
+    repo.delete(claims.tenant(), claims.sub(), id).await?;
 
-    post.delete(conn).await.unwrap();
+    Ok(Json(FlashData::success("Post succcessfully deleted")))
+}
 
-    let data = FlashData {
-        kind: "success".to_owned(),
-        message: "Post succcessfully deleted".to_owned(),
-    };
+impl Params {
+    pub fn page(&self) -> usize {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn posts_per_page(&self) -> usize {
+        self.posts_per_page.unwrap_or(5).max(1)
+    }
 
-    Json(data)
+    pub fn tag_filter(&self) -> TagFilter {
+        let mut tags: Vec<String> = self
+            .tags
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(tag::normalize)
+            .filter(|name| !name.is_empty())
+            .collect();
+        tags.sort();
+        tags.dedup();
+
+        TagFilter {
+            tags,
+            all: self.tag_match == Some(TagMatch::All),
+        }
+    }
 }
+
+/// Failed post query of the JSON API.
+#[derive(Debug)]
+pub struct PostError(DbErr);
+
+impl From<DbErr> for PostError {
+    fn from(err: DbErr) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for PostError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self.0 {
+            DbErr::RecordNotFound(_) => (StatusCode::NOT_FOUND, "Post not found"),
+            err => {
+                tracing::error!("database error: {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+            }
+        };
+        let body = Json(json!({
+            "error": error_message,
+        }));
+        (status, body).into_response()
+    }
+}
+
+impl FlashData {
+    pub fn success(message: &str) -> Self {
+        Self {
+            kind: "success".to_owned(),
+            message: message.to_owned(),
+        }
+    }
+
+    pub fn error(message: &str) -> Self {
+        Self {
+            kind: "error".to_owned(),
+            message: message.to_owned(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::marker::PhantomData;
+
+    use axum::http::{header, Method, StatusCode};
+    use serde_json::json;
 
     use super::*;
+    use crate::post_repository::InMemoryPostRepository;
+    use crate::test_app::{TestApp, EMAIL, OTHER_TENANT_EMAIL, SECRET};
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "account@example.com".to_owned(),
+            company: 1,
+            scope: "posts:read posts:write".to_owned(),
+            exp: 2000000000,
+            api_key: false,
+        }
+    }
+
+    fn scoped<S>() -> RequireScope<S> {
+        RequireScope(claims(), PhantomData)
+    }
+
+    async fn json_body(response: impl IntoResponse) -> serde_json::Value {
+        let body = hyper::body::to_bytes(response.into_response().into_body())
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
 
-    use migration::{Migrator, MigratorTrait};
-    use sea_orm::Database;
-    use serde_json::json;
     #[tokio::test]
-    async fn hello_world() {
-        let conn = Database::connect("sqlite::memory:".to_string())
-            .await
-            .expect("Database connection failed");
-        Migrator::up(&conn, None).await.unwrap();
-
-        //list
-        let page = 1;
-        let posts_per_page = 5;
-        let paginator = Posts::find()
-            .order_by_asc(posts::Column::Id)
-            .paginate(&conn, posts_per_page);
-        let posts = paginator
-            .fetch_page(page - 1)
-            .await
-            .expect("could not retrieve posts");
-        assert_eq!(0, posts.len());
-        assert_eq!(json!(posts), json!([]));
-
-        // create
-        posts::ActiveModel {
-            title: Set("title11".to_owned()),
-            text: Set("text11".to_owned()),
-            new_col: Set(17),
-            ..Default::default()
+    async fn handlers_use_the_repository() {
+        let repo: DynPostRepository = Arc::new(InMemoryPostRepository::default());
+        let input = posts::Model {
+            id: 0,
+            title: "title11".to_owned(),
+            text: "text11".to_owned(),
+            new_col: 17,
+            tenant_id: 0,
+        };
+
+        let response = api_create_post(scoped(), Extension(repo.clone()), Json(input)).await;
+        assert_eq!(
+            json_body(response).await,
+            json!({"kind": "success", "message": "Post succcessfully added"})
+        );
+
+        let params = Params {
+            page: None,
+            posts_per_page: None,
+            tags: None,
+            tag_match: None,
+        };
+        let response = api_list_posts(scoped(), Extension(repo.clone()), Query(params)).await;
+        assert_eq!(
+            json_body(response).await,
+            json!({
+                "posts": [{"id": 1, "title": "title11", "text": "text11", "new_col": 17}],
+                "page": 1,
+                "posts_per_page": 5,
+                "num_pages": 1,
+            })
+        );
+
+        api_delete_post(scoped(), Extension(repo.clone()), Path(1))
+            .await
+            .unwrap();
+        assert_eq!(repo.get(1, 1).await.unwrap(), None);
+
+        let response = api_delete_post(scoped(), Extension(repo.clone()), Path(1)).await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn page_size_is_at_least_one() {
+        let params = |posts_per_page| Params {
+            page: None,
+            posts_per_page,
+            tags: None,
+            tag_match: None,
+        };
+        assert_eq!(params(None).posts_per_page(), 5);
+        assert_eq!(params(Some(0)).posts_per_page(), 1);
+        assert_eq!(params(Some(20)).posts_per_page(), 20);
+    }
+
+    #[test]
+    fn tag_filter() {
+        let params = |tags: Option<&str>, tag_match| Params {
+            page: None,
+            posts_per_page: None,
+            tags: tags.map(str::to_owned),
+            tag_match,
+        };
+        assert_eq!(params(None, None).tag_filter(), TagFilter::default());
+        assert_eq!(
+            params(Some(" Rust,axum,,rust"), Some(TagMatch::All)).tag_filter(),
+            TagFilter {
+                tags: vec!["axum".to_owned(), "rust".to_owned()],
+                all: true,
+            }
+        );
+        assert!(!params(Some("rust"), Some(TagMatch::Any)).tag_filter().all);
+    }
+
+    #[test]
+    fn tokens_from_before_tenants_and_scopes_still_decode() {
+        let legacy: Claims = serde_json::from_value(serde_json::json!({
+            "sub": "account@example.com",
+            "company": "ACME",
+            "exp": 2000000000,
+        }))
+        .unwrap();
+        assert_eq!(legacy.tenant(), LEGACY_TENANT);
+        assert_eq!(legacy.scope(), scope::DEFAULT_USER_SCOPES);
+        assert!(serde_json::from_value::<Claims>(serde_json::json!({
+            "sub": "account@example.com",
+            "company": "Globex",
+            "exp": 2000000000,
+        }))
+        .is_err());
+
+        let claims: Claims =
+            serde_json::from_value(serde_json::to_value(claims()).unwrap()).unwrap();
+        assert_eq!(claims.tenant(), 1);
+        assert_eq!(claims.scope(), "posts:read posts:write");
+    }
+
+    #[tokio::test]
+    async fn authorize() {
+        let app = TestApp::new().await;
+
+        let response = app
+            .authorize(EMAIL, SECRET)
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(response.json()["token_type"], "Bearer");
+        let token = response.json()["access_token"].as_str().unwrap().to_owned();
+        let expires_in = Claims::decode(&token, AuthSource::Bearer)
+            .unwrap()
+            .expires_at()
+            .unwrap()
+            - chrono::Utc::now();
+        let lifetime = TokenConfig::default().access_lifetime.as_secs() as i64;
+        assert!((lifetime - 5..=lifetime).contains(&expires_in.num_seconds()));
+
+        app.authorize(EMAIL, "wrong")
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Wrong credentials");
+        app.authorize("nobody@example.com", SECRET)
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Wrong credentials");
+        app.authorize(EMAIL, "")
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Missing credentials");
+        app.post("/authorize")
+            .json(json!({ "client_id": EMAIL }))
+            .send()
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn api_requires_a_token() {
+        let app = TestApp::new().await;
+        let post = json!({"title": "title11", "text": "text11", "new_col": 17});
+        let invalid_token = r#"Bearer error="invalid_token""#;
+
+        let response = app
+            .get("/api/")
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+        assert_eq!(response.header(header::WWW_AUTHENTICATE), invalid_token);
+        let response = app
+            .get("/api/")
+            .bearer("not-a-token")
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+        assert_eq!(response.header(header::WWW_AUTHENTICATE), invalid_token);
+        app.post("/api/")
+            .json(post.clone())
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+        app.patch("/api/1")
+            .json(post)
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+        app.delete("/api/1")
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Invalid token");
+
+        let response = app
+            .get("/api/")
+            .bearer(&app.expired_token())
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Expired token");
+        assert_eq!(
+            response.header(header::WWW_AUTHENTICATE),
+            r#"Bearer error="invalid_token", error_description="expired""#
+        );
+    }
+
+    #[tokio::test]
+    async fn json() {
+        let app = TestApp::new().await;
+        let token = app.token().await;
+
+        // - list
+        let response = app
+            .get("/api/")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(
+            response.json(),
+            json!({"num_pages": 0, "page": 1, "posts": [], "posts_per_page": 5})
+        );
+
+        // - new
+        for title in ["title11", "title12"] {
+            let response = app
+                .post("/api/")
+                .bearer(&token)
+                .json(json!({"title": title, "text": "text11", "new_col": 17}))
+                .send()
+                .await
+                .assert_status(StatusCode::OK);
+            assert_eq!(
+                response.json(),
+                json!({"kind": "success", "message": "Post succcessfully added"})
+            );
         }
-        .save(&conn)
-        .await
-        .expect("could not insert post");
 
-        //list
-        let page = 1;
-        let posts_per_page = 5;
-        let paginator = Posts::find()
-            .order_by_asc(posts::Column::Id)
-            .paginate(&conn, posts_per_page);
-        let posts = paginator
-            .fetch_page(page - 1)
+        // - list
+        let response = app
+            .get("/api/?page=2&posts_per_page=1")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(
+            response.json(),
+            json!({
+                "num_pages": 2,
+                "page": 2,
+                "posts": [{"id": 2, "title": "title12", "text": "text11", "new_col": 17}],
+                "posts_per_page": 1,
+            })
+        );
+
+        // - update
+        let response = app
+            .patch("/api/1")
+            .bearer(&token)
+            .json(json!({"title": "updated", "text": "text11", "new_col": 4}))
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(
+            response.json(),
+            json!({"kind": "success", "message": "Post succcessfully updated"})
+        );
+        let post = entity::posts::Entity::find_by_id(1)
+            .one(&app.conn)
             .await
-            .expect("could not retrieve posts");
-        assert_eq!(1, posts.len());
-        assert_eq!(posts[0].title, "title11");
-        assert_eq!(posts[0].text, "text11");
-        assert_eq!(posts[0].new_col, 17);
+            .unwrap()
+            .unwrap();
+        assert_eq!((post.title.as_str(), post.new_col), ("updated", 4));
+
+        // - delete
+        let response = app
+            .delete("/api/1")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(
+            response.json(),
+            json!({"kind": "success", "message": "Post succcessfully deleted"})
+        );
+
+        // - list
+        let response = app
+            .get("/api/")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(response.json()["posts"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tenants_are_isolated() {
+        let app = TestApp::new().await;
+        let token = app.token().await;
+        let other = app.token_for(OTHER_TENANT_EMAIL).await;
+        let post = json!({"title": "title11", "text": "text11", "new_col": 17});
+
+        app.post("/api/")
+            .bearer(&token)
+            .json(post.clone())
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+
+        // The other tenant can neither see nor touch post 1
+        let response = app
+            .get("/api/")
+            .bearer(&other)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(response.json()["posts"], json!([]));
+        app.patch("/api/1")
+            .bearer(&other)
+            .json(json!({"title": "stolen", "text": "stolen", "new_col": 0}))
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+        app.delete("/api/1")
+            .bearer(&other)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+
+        // and its own posts stay its own
+        app.post("/api/")
+            .bearer(&other)
+            .json(post)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        let response = app
+            .get("/api/")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(
+            response.json()["posts"],
+            json!([{"id": 1, "title": "title11", "text": "text11", "new_col": 17}])
+        );
+        let posts = entity::posts::Entity::find().all(&app.conn).await.unwrap();
+        let tenants: Vec<_> = posts.iter().map(|post| (post.id, post.tenant_id)).collect();
+        assert_eq!(tenants, vec![(1, 1), (2, 2)]);
+        app.delete("/api/2")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+    }
+
+    #[tokio::test]
+    async fn json_errors() {
+        let app = TestApp::new().await;
+        let token = app.token().await;
+        let post = json!({"title": "title11", "text": "text11", "new_col": 17});
+
+        app.patch("/api/42")
+            .bearer(&token)
+            .json(post)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+        app.delete("/api/42")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+        app.delete("/api/abc")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        app.post("/api/")
+            .bearer(&token)
+            .json(json!({ "title": "title11" }))
+            .send()
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        app.post("/api/")
+            .bearer(&token)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        app.request(Method::PUT, "/api/1")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::METHOD_NOT_ALLOWED);
+        app.get("/nowhere")
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
     }
 }
 
+// curl -H 'Content-Type: application/json' http://localhost:8000/authorize --data '{"client_id":"account@example.com","client_secret":"secret"}'
+#[utoipa::path(
+    post,
+    path = "/authorize",
+    tag = "auth",
+    request_body = AuthPayload,
+    responses(
+        (status = 200, description = "Access token to send as `Authorization: Bearer`, or a challenge to complete at `/authorize/mfa` for users with two-factor authentication", body = AuthorizeResponse),
+        (status = 400, description = "Missing credentials", body = ErrorBody),
+        (status = 401, description = "Wrong credentials", body = ErrorBody),
+        (status = 429, description = "Too many attempts, retry after `Retry-After` seconds", body = ErrorBody),
+    )
+)]
 pub async fn authorize_user(
     Json(payload): Json<AuthPayload>,
     Extension(ref conn): Extension<DatabaseConnection>,
-) -> Result<Json<AuthBody>, AuthError> {
+    Extension(limiter): Extension<Arc<RateLimiter>>,
+    Extension(config): Extension<TokenConfig>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> Result<Json<AuthorizeResponse>, AuthError> {
+    let ip = rate_limit::client_ip(connect_info.as_ref());
+    limiter.check_login(&ip, &payload.client_id).await?;
+    let user = limiter
+        .record_login(
+            &payload.client_id,
+            verify_credentials(conn, &payload.client_id, &payload.client_secret).await,
+        )
+        .await?;
+    if user.totp_enabled_at.is_some() {
+        let challenge = mfa::challenge(conn, &user).await?;
+        return Ok(Json(AuthorizeResponse::MfaRequired(challenge)));
+    }
+    // Create the authorization token
+    let token = issue_token(&user, &config)?;
+
+    // Send the authorized token
+    Ok(Json(AuthorizeResponse::Token(AuthBody::new(token))))
+}
+
+/// Look up the user by email and check the secret against the stored hash.
+pub async fn verify_credentials(
+    conn: &DatabaseConnection,
+    email: &str,
+    secret: &str,
+) -> Result<user::Model, AuthError> {
     // Check if the user sent the credentials
-    if payload.client_id.is_empty() || payload.client_secret.is_empty() {
+    if email.is_empty() || secret.is_empty() {
         return Err(AuthError::MissingCredentials);
     }
     // Here you can check the user credentials from a database
-    let user: user::Model = User::find()
-        .filter(user::Column::Email.eq(payload.client_id))
+    let user = User::find()
+        .filter(user::Column::Email.eq(email))
         .one(conn)
         .await
-        .expect("could not find user")
-        .unwrap();
-    let tag = hmac::sign(&KEY, payload.client_secret.as_bytes());
-    let client_secret_hash = base64::encode(tag.as_ref());
-    tracing::info!(
-        "user.hash: {:?}, client_secret_hash: {:?}",
-        user.hash,
-        client_secret_hash
-    );
-    if user.hash != client_secret_hash {
-        return Err(AuthError::WrongCredentials);
-    }
-    let claims = Claims {
-        sub: "b@b.com".to_owned(),
-        company: "ACME".to_owned(),
-        // Mandatory expiry time as UTC timestamp
-        exp: 2000000000, // May 2033
+        .map_err(|e| {
+            tracing::error!("could not find user: {}", e);
+            AuthError::Database
+        })?;
+    // Hash and compare even for unknown emails so that both failures take
+    // the same time
+    let expected = user
+        .as_ref()
+        .map_or(UNKNOWN_USER_HASH, |user| user.hash.as_str());
+    let matches = ring::constant_time::verify_slices_are_equal(
+        hash_secret(secret).as_bytes(),
+        expected.as_bytes(),
+    )
+    .is_ok();
+    let user = match user {
+        Some(user) if matches => user,
+        Some(user) => {
+            tracing::info!(user_id = user.id, "wrong credentials");
+            metrics::record_auth(AuthSource::Authorize, false);
+            return Err(AuthError::WrongCredentials);
+        }
+        None => {
+            metrics::record_auth(AuthSource::Authorize, false);
+            return Err(AuthError::WrongCredentials);
+        }
     };
-    // Create the authorization token
-    let token = encode(&Header::default(), &claims, &KEYS.encoding)
-        .map_err(|_| AuthError::TokenCreation)?;
+    metrics::record_auth(AuthSource::Authorize, true);
 
-    // Send the authorized token
-    Ok(Json(AuthBody::new(token)))
+    Ok(user)
+}
+
+/// Stands in for the hash of unknown users; as long as a real one but never
+/// produced by `hash_secret`, since `*` is not a base64 character.
+const UNKNOWN_USER_HASH: &str = "********************************************";
+
+/// Hash a client secret the same way it is stored in `user.hash`.
+pub fn hash_secret(secret: &str) -> String {
+    let tag = hmac::sign(&KEY, secret.as_bytes());
+    base64::encode(tag.as_ref())
+}
+
+/// Sign an access token for the given user, carrying all of their scopes,
+/// that expires after the configured access token lifetime.
+pub fn issue_token(user: &user::Model, config: &TokenConfig) -> Result<String, AuthError> {
+    issue_token_expiring_in(user, &user.scopes, config.access_lifetime)
+}
+
+/// Sign an access token for the given user with the space-separated `scope`
+/// that expires after `lifetime`.
+pub fn issue_token_expiring_in(
+    user: &user::Model,
+    scope: &str,
+    lifetime: Duration,
+) -> Result<String, AuthError> {
+    let exp = chrono::Utc::now().timestamp() as u64 + lifetime.as_secs();
+    sign_token(user, scope, exp as usize)
+}
+
+fn sign_token(user: &user::Model, scope: &str, exp: usize) -> Result<String, AuthError> {
+    let claims = Claims::new(user, scope, exp);
+    KEYS.encode(&claims).map_err(|_| AuthError::TokenCreation)
+}
+
+// curl http://localhost:8000/.well-known/jwks.json
+/// Public keys verifying our tokens, empty unless `JWT_KEYS_FILE` is set.
+pub async fn jwks() -> Json<JwkSet> {
+    Json(KEYS.jwks())
+}
+
+impl Claims {
+    /// Claims of `user` with the space-separated `scope`, valid until the UTC
+    /// timestamp `exp`.
+    pub fn new(user: &user::Model, scope: &str, exp: usize) -> Self {
+        Self {
+            sub: user.email.to_owned(),
+            company: user.tenant_id,
+            scope: scope.to_owned(),
+            exp,
+            api_key: false,
+        }
+    }
+
+    /// Claims of `user` authenticated with a personal API key, see `new`.
+    pub fn for_api_key(user: &user::Model, scope: &str, exp: usize) -> Self {
+        Self {
+            api_key: true,
+            ..Self::new(user, scope, exp)
+        }
+    }
+
+    /// Email of the authenticated user
+    pub fn sub(&self) -> &str {
+        &self.sub
+    }
+
+    /// Id of the user's tenant; posts of other tenants are out of reach
+    pub fn tenant(&self) -> i32 {
+        self.company
+    }
+
+    /// Space-separated scopes of the token
+    pub fn scope(&self) -> &str {
+        &self.scope
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        scope::contains(&self.scope, scope)
+    }
+
+    /// When the token stops working
+    pub fn expires_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        use chrono::TimeZone;
+
+        let exp = i64::try_from(self.exp).ok()?;
+        chrono::Utc.timestamp_opt(exp, 0).single()
+    }
+
+    /// Whether the caller sent a personal API key instead of a token
+    pub fn is_api_key(&self) -> bool {
+        self.api_key
+    }
 }
 
 impl Display for Claims {
@@ -252,7 +850,7 @@ impl Display for Claims {
 }
 
 impl AuthBody {
-    fn new(access_token: String) -> Self {
+    pub fn new(access_token: String) -> Self {
         Self {
             access_token,
             token_type: "Bearer".to_string(),
@@ -268,14 +866,53 @@ where
     type Rejection = AuthError;
 
     async fn from_request(req: &mut RequestParts<S>) -> Result<Self, Self::Rejection> {
-        // Extract the token from the authorization header
-        let TypedHeader(Authorization(bearer)) =
-            TypedHeader::<Authorization<Bearer>>::from_request(req)
-                .await
-                .map_err(|_| AuthError::InvalidToken)?;
-        // Decode the user data
-        let token_data = decode::<Claims>(bearer.token(), &KEYS.decoding, &Validation::default())
-            .map_err(|_| AuthError::InvalidToken)?;
+        // Extract the token from the authorization header, or from the
+        // session cookie set by the login form
+        let (token, source) = match TypedHeader::<Authorization<Bearer>>::from_request(req).await {
+            Ok(TypedHeader(Authorization(bearer))) => {
+                (bearer.token().to_owned(), AuthSource::Bearer)
+            }
+            Err(_) => {
+                let token = req
+                    .extensions()
+                    .get::<Cookies>()
+                    .and_then(session::token_from_cookies)
+                    .ok_or_else(|| {
+                        metrics::record_auth(AuthSource::Bearer, false);
+                        AuthError::InvalidToken
+                    })?;
+                (token, AuthSource::Session)
+            }
+        };
+        // Personal API keys are sent like tokens but looked up in the database
+        if matches!(source, AuthSource::Bearer) && token.starts_with(api_key::PREFIX) {
+            let conn = req
+                .extensions()
+                .get::<DatabaseConnection>()
+                .ok_or(AuthError::Database)?;
+            let result = api_key::authenticate(conn, &token).await;
+            metrics::record_auth(AuthSource::ApiKey, result.is_ok());
+            let claims = result?;
+            tracing::Span::current().record("user", &tracing::field::display(&claims.sub));
+            return Ok(claims);
+        }
+
+        Claims::decode(&token, source)
+    }
+}
+
+impl Claims {
+    /// Claims of a JWT presented through `source`.
+    pub fn decode(token: &str, source: AuthSource) -> Result<Self, AuthError> {
+        let token_data = KEYS.decode::<Claims>(token).map_err(|e| {
+            metrics::record_auth(source, false);
+            match e.kind() {
+                ErrorKind::ExpiredSignature => AuthError::ExpiredToken,
+                _ => AuthError::InvalidToken,
+            }
+        })?;
+        metrics::record_auth(source, true);
+        tracing::Span::current().record("user", &tracing::field::display(&token_data.claims.sub));
 
         Ok(token_data.claims)
     }
@@ -283,56 +920,159 @@ where
 
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "Wrong credentials"),
-            AuthError::MissingCredentials => (StatusCode::BAD_REQUEST, "Missing credentials"),
-            AuthError::TokenCreation => (StatusCode::INTERNAL_SERVER_ERROR, "Token creation error"),
-            AuthError::InvalidToken => (StatusCode::BAD_REQUEST, "Invalid token"),
+        let (status, error_message, challenge) = match self {
+            AuthError::TooManyAttempts(wait) => {
+                let body = Json(json!({
+                    "error": "Too many attempts",
+                }));
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, rate_limit::retry_after(wait))],
+                    body,
+                )
+                    .into_response();
+            }
+            AuthError::InsufficientScope(scope) => {
+                let body = Json(json!({
+                    "error": "Insufficient scope",
+                }));
+                let challenge = format!(r#"Bearer error="insufficient_scope", scope="{}""#, scope);
+                return (
+                    StatusCode::FORBIDDEN,
+                    [(header::WWW_AUTHENTICATE, challenge)],
+                    body,
+                )
+                    .into_response();
+            }
+            AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "Wrong credentials", None),
+            AuthError::MissingCredentials => (StatusCode::BAD_REQUEST, "Missing credentials", None),
+            AuthError::TokenCreation => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Token creation error",
+                None,
+            ),
+            AuthError::Database => (StatusCode::INTERNAL_SERVER_ERROR, "Database error", None),
+            // RFC 6750 challenges, so clients know to get a new token
+            AuthError::InvalidToken => (
+                StatusCode::UNAUTHORIZED,
+                "Invalid token",
+                Some(r#"Bearer error="invalid_token""#),
+            ),
+            AuthError::ExpiredToken => (
+                StatusCode::UNAUTHORIZED,
+                "Expired token",
+                Some(r#"Bearer error="invalid_token", error_description="expired""#),
+            ),
         };
         let body = Json(json!({
             "error": error_message,
         }));
-        (status, body).into_response()
-    }
-}
-
-struct Keys {
-    encoding: EncodingKey,
-    decoding: DecodingKey,
-}
-
-impl Keys {
-    fn new(secret: &[u8]) -> Self {
-        Self {
-            encoding: EncodingKey::from_secret(secret),
-            decoding: DecodingKey::from_secret(secret),
+        let mut response = (status, body).into_response();
+        if let Some(challenge) = challenge {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static(challenge),
+            );
         }
+        response
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     sub: String,
-    company: String,
+    /// Id of the user's tenant
+    #[serde(deserialize_with = "deserialize_company")]
+    company: i32,
+    /// Space-separated scopes, see `scope::Scope`; tokens from before scopes
+    /// get those of a new user
+    #[serde(default = "default_scope")]
+    scope: String,
     exp: usize,
+    /// Never part of a token, set by `api_key::authenticate`
+    #[serde(skip)]
+    api_key: bool,
+}
+
+/// Tokens issued before tenants carry this company name instead of an id.
+const LEGACY_COMPANY: &str = "ACME";
+/// Tenant the posts of `LEGACY_COMPANY` moved to
+const LEGACY_TENANT: i32 = 1;
+
+fn deserialize_company<'de, D>(deserializer: D) -> Result<i32, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Company {
+        Id(i32),
+        Name(String),
+    }
+
+    match Company::deserialize(deserializer)? {
+        Company::Id(id) => Ok(id),
+        Company::Name(name) if name == LEGACY_COMPANY => Ok(LEGACY_TENANT),
+        Company::Name(name) => Err(serde::de::Error::custom(format!(
+            "unknown company {}",
+            name
+        ))),
+    }
+}
+
+fn default_scope() -> String {
+    scope::DEFAULT_USER_SCOPES.to_owned()
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct AuthBody {
     access_token: String,
     token_type: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum AuthorizeResponse {
+    Token(AuthBody),
+    MfaRequired(MfaChallenge),
+}
+
+impl std::fmt::Debug for AuthBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthBody")
+            .field("access_token", &"[REDACTED]")
+            .field("token_type", &self.token_type)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct AuthPayload {
     client_id: String,
     client_secret: String,
 }
 
+// Never let the secret reach the logs
+impl std::fmt::Debug for AuthPayload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthPayload")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"[REDACTED]")
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 pub enum AuthError {
     WrongCredentials,
     MissingCredentials,
     TokenCreation,
+    /// Missing, malformed or wrongly signed token
     InvalidToken,
+    ExpiredToken,
+    /// Valid token without the scope the route requires
+    InsufficientScope(Scope),
+    Database,
+    /// Rate limited or locked out, retry after the given time
+    TooManyAttempts(Duration),
 }
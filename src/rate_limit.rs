@@ -0,0 +1,548 @@
+//! Rate limiting of logins and of the JSON API.
+//!
+//! Every limit is a token bucket refilled evenly over a minute; a quota of
+//! `0` disables it:
+//!
+//! - `LOGIN_IP_PER_MINUTE` attempts at `/authorize` and the login form per
+//!   client address (default `20`)
+//! - `LOGIN_ACCOUNT_PER_MINUTE` attempts per account, whatever the address
+//!   (default `10`)
+//! - `API_IP_PER_MINUTE` requests to `/api` per client address (default `600`)
+//! - `RESET_IP_PER_MINUTE` password reset requests per client address
+//!   (default `5`) and `RESET_ACCOUNT_PER_MINUTE` per account (default `3`),
+//!   in buckets of their own so that they can't lock anyone out of logging in
+//!
+//! After `LOCKOUT_AFTER` consecutive wrong secrets (default `5`) the account
+//! is locked for `LOCKOUT_SECONDS` (default `30`), doubling with every further
+//! failure up to an hour. Refused requests get `429 Too Many Requests` with a
+//! `Retry-After` header.
+//!
+//! State lives behind `RateLimitStore`; `InMemoryStore` is enough for a single
+//! instance, several instances need a shared implementation.
+
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    async_trait,
+    extract::ConnectInfo,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+use crate::post_service::AuthError;
+
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Failures older than this no longer count towards a lockout.
+const FAILURE_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub type DynRateLimitStore = Arc<dyn RateLimitStore>;
+
+/// `burst` requests, refilled evenly over `period`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    pub fn per_minute(burst: u32) -> Option<Self> {
+        (burst > 0).then(|| Self {
+            burst,
+            period: Duration::from_secs(60),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub login_per_ip: Option<Quota>,
+    pub login_per_account: Option<Quota>,
+    pub api_per_ip: Option<Quota>,
+    pub reset_per_ip: Option<Quota>,
+    pub reset_per_account: Option<Quota>,
+    pub lockout_after: u32,
+    pub lockout: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            login_per_ip: Quota::per_minute(20),
+            login_per_account: Quota::per_minute(10),
+            api_per_ip: Quota::per_minute(600),
+            reset_per_ip: Quota::per_minute(5),
+            reset_per_account: Quota::per_minute(3),
+            lockout_after: 5,
+            lockout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Self {
+        let number = |name: &str| {
+            env::var(name).ok().map(|value| {
+                value
+                    .parse::<u32>()
+                    .unwrap_or_else(|_| panic!("{} must be a number", name))
+            })
+        };
+        let default = Self::default();
+        let quota = |name: &str, default: Option<Quota>| match number(name) {
+            Some(burst) => Quota::per_minute(burst),
+            None => default,
+        };
+
+        Self {
+            login_per_ip: quota("LOGIN_IP_PER_MINUTE", default.login_per_ip),
+            login_per_account: quota("LOGIN_ACCOUNT_PER_MINUTE", default.login_per_account),
+            api_per_ip: quota("API_IP_PER_MINUTE", default.api_per_ip),
+            reset_per_ip: quota("RESET_IP_PER_MINUTE", default.reset_per_ip),
+            reset_per_account: quota("RESET_ACCOUNT_PER_MINUTE", default.reset_per_account),
+            lockout_after: number("LOCKOUT_AFTER").unwrap_or(default.lockout_after),
+            lockout: number("LOCKOUT_SECONDS")
+                .map(|seconds| Duration::from_secs(seconds.into()))
+                .unwrap_or(default.lockout),
+        }
+    }
+
+    /// Lockout after the `failures`th consecutive wrong secret, if any.
+    fn lockout_for(&self, failures: u32) -> Option<Duration> {
+        if self.lockout_after == 0 || failures < self.lockout_after {
+            return None;
+        }
+        let doublings = (failures - self.lockout_after).min(16);
+        Some((self.lockout * 2u32.pow(doublings)).min(MAX_LOCKOUT))
+    }
+}
+
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Take a token from the bucket `key`, or tell how long until one is back.
+    async fn take(&self, key: &str, quota: Quota) -> Result<(), Duration>;
+
+    /// Count a failed login and return the number of consecutive failures.
+    async fn add_failure(&self, key: &str) -> u32;
+
+    async fn lock(&self, key: &str, duration: Duration);
+
+    /// Remaining lockout of `key`.
+    async fn locked(&self, key: &str) -> Option<Duration>;
+
+    /// Forget the failures and lockout of `key` after a successful login.
+    async fn reset(&self, key: &str);
+
+    /// Drop state that no longer affects any decision.
+    async fn prune(&self) {}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(quota: Quota, now: Instant) -> Self {
+        Self {
+            tokens: quota.burst.into(),
+            updated: now,
+        }
+    }
+
+    fn take(&mut self, quota: Quota, now: Instant) -> Result<(), Duration> {
+        let per_token = quota.period.as_secs_f64() / f64::from(quota.burst);
+        let refilled = now.duration_since(self.updated).as_secs_f64() / per_token;
+        self.tokens = (self.tokens + refilled).min(quota.burst.into());
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) * per_token))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Buckets and failures of this process.
+#[derive(Default)]
+pub struct InMemoryStore {
+    buckets: Mutex<HashMap<String, (Bucket, Quota)>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+#[async_trait]
+impl RateLimitStore for InMemoryStore {
+    async fn take(&self, key: &str, quota: Quota) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let (bucket, _) = buckets
+            .entry(key.to_owned())
+            .or_insert_with(|| (Bucket::full(quota, now), quota));
+        bucket.take(quota, now)
+    }
+
+    async fn add_failure(&self, key: &str) -> u32 {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.entry(key.to_owned()).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if now.duration_since(entry.last) > FAILURE_TTL {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        entry.count
+    }
+
+    async fn lock(&self, key: &str, duration: Duration) {
+        let mut failures = self.failures.lock().unwrap();
+        if let Some(entry) = failures.get_mut(key) {
+            entry.locked_until = Some(Instant::now() + duration);
+        }
+    }
+
+    async fn locked(&self, key: &str) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let until = failures.get(key)?.locked_until?;
+        until
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    async fn reset(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+
+    async fn prune(&self) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, (bucket, quota)| now.duration_since(bucket.updated) < quota.period);
+        self.failures.lock().unwrap().retain(|_, failures| {
+            now.duration_since(failures.last) < FAILURE_TTL
+                || failures.locked_until.is_some_and(|until| until > now)
+        });
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    store: DynRateLimitStore,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, store: DynRateLimitStore) -> Self {
+        Self { config, store }
+    }
+
+    /// Check the limits of a login attempt before looking at the credentials.
+    pub async fn check_login(&self, ip: &str, account: &str) -> Result<(), AuthError> {
+        if let Some(quota) = self.config.login_per_ip {
+            self.store
+                .take(&format!("login-ip:{}", ip), quota)
+                .await
+                .map_err(AuthError::TooManyAttempts)?;
+        }
+        let account = account_key(account);
+        if let Some(remaining) = self.store.locked(&account).await {
+            return Err(AuthError::TooManyAttempts(remaining));
+        }
+        if let Some(quota) = self.config.login_per_account {
+            self.store
+                .take(&format!("login-{}", account), quota)
+                .await
+                .map_err(AuthError::TooManyAttempts)?;
+        }
+
+        Ok(())
+    }
+
+    /// Count wrong secrets towards the lockout of `account`, and clear them
+    /// once the right one is given.
+    pub async fn record_login<T>(
+        &self,
+        account: &str,
+        result: Result<T, AuthError>,
+    ) -> Result<T, AuthError> {
+        let key = account_key(account);
+        match &result {
+            Ok(_) => self.store.reset(&key).await,
+            Err(AuthError::WrongCredentials) => {
+                let failures = self.store.add_failure(&key).await;
+                if let Some(duration) = self.config.lockout_for(failures) {
+                    tracing::warn!(failures, ?duration, "locking account after failed logins");
+                    self.store.lock(&key, duration).await;
+                }
+            }
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    /// Check the limits of a password reset request; unlike `check_login` it
+    /// ignores lockouts and leaves the login buckets alone.
+    pub async fn check_reset(&self, ip: &str, account: &str) -> Result<(), AuthError> {
+        if let Some(quota) = self.config.reset_per_ip {
+            self.store
+                .take(&format!("reset-ip:{}", ip), quota)
+                .await
+                .map_err(AuthError::TooManyAttempts)?;
+        }
+        if let Some(quota) = self.config.reset_per_account {
+            self.store
+                .take(&format!("reset-{}", account_key(account)), quota)
+                .await
+                .map_err(AuthError::TooManyAttempts)?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn check_api(&self, ip: &str) -> Result<(), Duration> {
+        match self.config.api_per_ip {
+            Some(quota) => self.store.take(&format!("api-ip:{}", ip), quota).await,
+            None => Ok(()),
+        }
+    }
+}
+
+fn account_key(account: &str) -> String {
+    format!("account:{}", account.trim().to_lowercase())
+}
+
+/// Address of the client, as seen by the server.
+pub fn client_ip(connect_info: Option<&ConnectInfo<SocketAddr>>) -> String {
+    connect_info.map_or_else(
+        || "unknown".to_owned(),
+        |ConnectInfo(addr)| addr.ip().to_string(),
+    )
+}
+
+/// Whole seconds, rounded up, to wait before retrying.
+pub fn retry_after(wait: Duration) -> HeaderValue {
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    HeaderValue::from(seconds.max(1))
+}
+
+/// Middleware limiting the requests of each client address, see
+/// `API_IP_PER_MINUTE`.
+pub async fn limit_api<B>(req: Request<B>, next: Next<B>) -> Response {
+    if let Some(limiter) = req.extensions().get::<Arc<RateLimiter>>() {
+        let ip = client_ip(req.extensions().get());
+        if let Err(wait) = limiter.check_api(&ip).await {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after(wait))],
+                Json(json!({
+                    "error": "Too many requests",
+                })),
+            )
+                .into_response();
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Periodically drop buckets and failures that have expired.
+pub async fn prune(limiter: Arc<RateLimiter>, token: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = interval.tick() => limiter.store.prune().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+
+    use super::*;
+    use crate::test_app::{TestApp, EMAIL, SECRET};
+
+    #[test]
+    fn bucket_refills_over_the_period() {
+        let quota = Quota {
+            burst: 2,
+            period: Duration::from_secs(10),
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::full(quota, start);
+
+        assert!(bucket.take(quota, start).is_ok());
+        assert!(bucket.take(quota, start).is_ok());
+        assert_eq!(bucket.take(quota, start), Err(Duration::from_secs(5)));
+        assert_eq!(
+            bucket.take(quota, start + Duration::from_secs(3)),
+            Err(Duration::from_secs(2))
+        );
+        assert!(bucket.take(quota, start + Duration::from_secs(6)).is_ok());
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_an_hour() {
+        let config = RateLimitConfig::default();
+        assert_eq!(config.lockout_for(4), None);
+        assert_eq!(config.lockout_for(5), Some(Duration::from_secs(30)));
+        assert_eq!(config.lockout_for(6), Some(Duration::from_secs(60)));
+        assert_eq!(config.lockout_for(8), Some(Duration::from_secs(240)));
+        assert_eq!(config.lockout_for(100), Some(MAX_LOCKOUT));
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        assert_eq!(retry_after(Duration::from_millis(1500)), "2");
+        assert_eq!(retry_after(Duration::from_secs(3)), "3");
+        assert_eq!(retry_after(Duration::ZERO), "1");
+    }
+
+    #[tokio::test]
+    async fn accounts_are_locked_after_repeated_failures() {
+        let config = RateLimitConfig {
+            login_per_ip: None,
+            login_per_account: None,
+            lockout_after: 2,
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(config, Arc::new(InMemoryStore::default()));
+
+        for _ in 0..2 {
+            limiter
+                .check_login("127.0.0.1", "a@example.com")
+                .await
+                .unwrap();
+            let _ = limiter
+                .record_login::<()>("a@example.com", Err(AuthError::WrongCredentials))
+                .await;
+        }
+        assert!(matches!(
+            limiter.check_login("127.0.0.1", "A@example.com").await,
+            Err(AuthError::TooManyAttempts(wait)) if wait <= Duration::from_secs(30)
+        ));
+        // Other accounts are not affected
+        limiter
+            .check_login("127.0.0.1", "b@example.com")
+            .await
+            .unwrap();
+
+        limiter.store.reset("account:a@example.com").await;
+        limiter
+            .check_login("127.0.0.1", "a@example.com")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn authorize_is_rate_limited() {
+        let app = TestApp::with_rate_limits(RateLimitConfig {
+            login_per_ip: Quota::per_minute(3),
+            login_per_account: None,
+            lockout_after: 2,
+            ..Default::default()
+        })
+        .await;
+
+        app.authorize(EMAIL, "wrong")
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Wrong credentials");
+        app.authorize(EMAIL, "wrong")
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, "Wrong credentials");
+        // Locked out, even with the right secret
+        let response = app
+            .authorize(EMAIL, SECRET)
+            .await
+            .assert_error(StatusCode::TOO_MANY_REQUESTS, "Too many attempts");
+        assert_eq!(response.header(header::RETRY_AFTER), "30");
+
+        // Out of attempts for this address, whatever the account
+        let response = app
+            .authorize("nobody@example.com", SECRET)
+            .await
+            .assert_error(StatusCode::TOO_MANY_REQUESTS, "Too many attempts");
+        assert_eq!(response.header(header::RETRY_AFTER), "20");
+    }
+
+    #[tokio::test]
+    async fn password_resets_are_limited_apart_from_logins() {
+        let app = TestApp::with_rate_limits(RateLimitConfig {
+            login_per_account: Quota::per_minute(1),
+            reset_per_account: Quota::per_minute(2),
+            ..Default::default()
+        })
+        .await;
+
+        for _ in 0..2 {
+            app.post("/password-reset")
+                .json(json!({ "email": EMAIL }))
+                .send()
+                .await
+                .assert_status(StatusCode::ACCEPTED);
+        }
+        app.post("/password-reset")
+            .json(json!({ "email": EMAIL }))
+            .send()
+            .await
+            .assert_error(StatusCode::TOO_MANY_REQUESTS, "Too many attempts");
+        // The owner of the account can still log in
+        app.authorize(EMAIL, SECRET)
+            .await
+            .assert_status(StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn api_is_rate_limited() {
+        let app = TestApp::with_rate_limits(RateLimitConfig {
+            api_per_ip: Quota::per_minute(2),
+            ..Default::default()
+        })
+        .await;
+        let token = app.token().await;
+
+        for _ in 0..2 {
+            app.get("/api/")
+                .bearer(&token)
+                .send()
+                .await
+                .assert_status(StatusCode::OK);
+        }
+        let response = app
+            .get("/api/")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_error(StatusCode::TOO_MANY_REQUESTS, "Too many requests");
+        assert_eq!(response.header(header::RETRY_AFTER), "30");
+
+        // Only the API is limited
+        app.get("/hello/")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+    }
+}
@@ -0,0 +1,323 @@
+//! Revision history of posts.
+//!
+//! Every update of a post keeps the version it replaced as a revision,
+//! numbered from 1 for the post as created. Revisions can be listed, fetched,
+//! compared line by line with each other or with the current post, and
+//! restored. A rollback is an update like any other: the version it replaces
+//! becomes a new revision and the change is audited.
+
+use axum::{
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use entity::post_revision;
+use entity::posts;
+use sea_orm::DbErr;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::diff::{self, DiffLine};
+use crate::post_repository::DynPostRepository;
+use crate::post_service::FlashData;
+use crate::scope::{PostsRead, PostsWrite, RequireScope};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DiffParams {
+    /// Revision to compare from
+    from: i32,
+    /// Revision to compare to, the current post if omitted
+    to: Option<i32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RevisionDiff {
+    from: i32,
+    /// `null` for the current post
+    to: Option<i32>,
+    title: Vec<DiffLine>,
+    text: Vec<DiffLine>,
+}
+
+// curl -H 'Authorization: Bearer ...' http://localhost:8000/api/1/revisions
+#[utoipa::path(
+    get,
+    path = "/api/{id}/revisions",
+    tag = "revisions",
+    params(("id" = i32, Path, description = "Post id")),
+    responses(
+        (status = 200, description = "Earlier versions of the post, oldest first", body = [post_revision::Revision]),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:read` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:read"]))
+)]
+pub async fn list_revisions(
+    RequireScope(claims, _): RequireScope<PostsRead>,
+    Extension(repo): Extension<DynPostRepository>,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<post_revision::Model>>, RevisionError> {
+    let revisions = repo.revisions(claims.tenant(), id).await?;
+
+    Ok(Json(revisions))
+}
+
+// curl -H 'Authorization: Bearer ...' http://localhost:8000/api/1/revisions/1
+#[utoipa::path(
+    get,
+    path = "/api/{id}/revisions/{number}",
+    tag = "revisions",
+    params(
+        ("id" = i32, Path, description = "Post id"),
+        ("number" = i32, Path, description = "Revision number"),
+    ),
+    responses(
+        (status = 200, description = "The revision", body = post_revision::Revision),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:read` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id or no such revision", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:read"]))
+)]
+pub async fn get_revision(
+    RequireScope(claims, _): RequireScope<PostsRead>,
+    Extension(repo): Extension<DynPostRepository>,
+    Path((id, number)): Path<(i32, i32)>,
+) -> Result<Json<post_revision::Model>, RevisionError> {
+    let revision = repo
+        .revision(claims.tenant(), id, number)
+        .await?
+        .ok_or(RevisionError::RevisionNotFound)?;
+
+    Ok(Json(revision))
+}
+
+// curl -H 'Authorization: Bearer ...' 'http://localhost:8000/api/1/diff?from=1&to=2'
+#[utoipa::path(
+    get,
+    path = "/api/{id}/diff",
+    tag = "revisions",
+    params(("id" = i32, Path, description = "Post id"), DiffParams),
+    responses(
+        (status = 200, description = "Line-level diff of the titles and texts", body = RevisionDiff),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:read` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id or no such revision", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:read"]))
+)]
+pub async fn diff_revisions(
+    RequireScope(claims, _): RequireScope<PostsRead>,
+    Extension(repo): Extension<DynPostRepository>,
+    Path(id): Path<i32>,
+    Query(params): Query<DiffParams>,
+) -> Result<Json<RevisionDiff>, RevisionError> {
+    let tenant = claims.tenant();
+    let old = repo
+        .revision(tenant, id, params.from)
+        .await?
+        .ok_or(RevisionError::RevisionNotFound)?;
+    let (title, text) = match params.to {
+        Some(number) => {
+            let new = repo
+                .revision(tenant, id, number)
+                .await?
+                .ok_or(RevisionError::RevisionNotFound)?;
+            (new.title, new.text)
+        }
+        None => {
+            let post = repo
+                .get(tenant, id)
+                .await?
+                .ok_or(RevisionError::PostNotFound)?;
+            (post.title, post.text)
+        }
+    };
+
+    Ok(Json(RevisionDiff {
+        from: params.from,
+        to: params.to,
+        title: diff::lines(&old.title, &title),
+        text: diff::lines(&old.text, &text),
+    }))
+}
+
+// curl -X POST -H 'Authorization: Bearer ...' http://localhost:8000/api/1/revisions/1/rollback
+#[utoipa::path(
+    post,
+    path = "/api/{id}/revisions/{number}/rollback",
+    tag = "revisions",
+    params(
+        ("id" = i32, Path, description = "Post id"),
+        ("number" = i32, Path, description = "Revision to restore"),
+    ),
+    responses(
+        (status = 200, description = "Post restored to the revision", body = FlashData),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:write` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id or no such revision", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:write"]))
+)]
+pub async fn rollback_post(
+    RequireScope(claims, _): RequireScope<PostsWrite>,
+    Extension(repo): Extension<DynPostRepository>,
+    Path((id, number)): Path<(i32, i32)>,
+) -> Result<Json<FlashData>, RevisionError> {
+    tracing::info!(id, number, "rolling back post");
+    let revision = repo
+        .revision(claims.tenant(), id, number)
+        .await?
+        .ok_or(RevisionError::RevisionNotFound)?;
+    let input = posts::Model {
+        id,
+        title: revision.title,
+        text: revision.text,
+        new_col: revision.new_col,
+        tenant_id: claims.tenant(),
+    };
+    repo.update(claims.tenant(), claims.sub(), id, input)
+        .await?;
+
+    Ok(Json(FlashData::success("Post successfully rolled back")))
+}
+
+#[derive(Debug)]
+pub enum RevisionError {
+    PostNotFound,
+    RevisionNotFound,
+    Database(DbErr),
+}
+
+impl From<DbErr> for RevisionError {
+    fn from(err: DbErr) -> Self {
+        match err {
+            DbErr::RecordNotFound(_) => Self::PostNotFound,
+            err => Self::Database(err),
+        }
+    }
+}
+
+impl IntoResponse for RevisionError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            RevisionError::PostNotFound => (StatusCode::NOT_FOUND, "Post not found"),
+            RevisionError::RevisionNotFound => (StatusCode::NOT_FOUND, "Revision not found"),
+            RevisionError::Database(err) => {
+                tracing::error!("database error: {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+            }
+        };
+        let body = Json(json!({
+            "error": error_message,
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use crate::test_app::{TestApp, EMAIL, OTHER_TENANT_EMAIL};
+
+    #[tokio::test]
+    async fn post_revisions() {
+        let app = TestApp::new().await;
+        let token = app.token().await;
+        let get = |uri: &str| app.get(uri).bearer(&token).send();
+        let update = |title: &str, text: &str| {
+            app.patch("/api/1")
+                .bearer(&token)
+                .json(json!({"title": title, "text": text, "new_col": 1}))
+                .send()
+        };
+
+        app.post("/api/")
+            .bearer(&token)
+            .json(json!({"title": "first", "text": "a\nb\nc", "new_col": 1}))
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        let response = get("/api/1/revisions").await.assert_status(StatusCode::OK);
+        assert_eq!(response.json(), json!([]));
+
+        update("second", "a\nc").await.assert_status(StatusCode::OK);
+        update("third", "a\nc\nd")
+            .await
+            .assert_status(StatusCode::OK);
+        let revisions = get("/api/1/revisions").await.json();
+        let versions: Vec<_> = revisions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|revision| json!([revision["number"], revision["title"]]))
+            .collect();
+        assert_eq!(versions, [json!([1, "first"]), json!([2, "second"])]);
+        assert_eq!(revisions[0]["replaced_by"], EMAIL);
+
+        let revision = get("/api/1/revisions/1")
+            .await
+            .assert_status(StatusCode::OK);
+        assert_eq!(revision.json()["text"], "a\nb\nc");
+        get("/api/1/revisions/3")
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Revision not found");
+
+        let diff = get("/api/1/diff?from=1&to=2").await.json();
+        assert_eq!(
+            diff["text"],
+            json!([
+                {"op": "equal", "line": "a"},
+                {"op": "delete", "line": "b"},
+                {"op": "equal", "line": "c"},
+            ])
+        );
+        assert_eq!(
+            diff["title"],
+            json!([{"op": "delete", "line": "first"}, {"op": "insert", "line": "second"}])
+        );
+        // Against the current post
+        let diff = get("/api/1/diff?from=2").await.json();
+        assert_eq!(diff["to"], Value::Null);
+        assert_eq!(diff["text"][2], json!({"op": "insert", "line": "d"}));
+        get("/api/1/diff?from=7")
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Revision not found");
+
+        app.post("/api/1/revisions/1/rollback")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        let page = get("/api/").await.json();
+        assert_eq!(page["posts"][0]["title"], "first");
+        assert_eq!(page["posts"][0]["text"], "a\nb\nc");
+        // The rolled back version is kept too
+        let revisions = get("/api/1/revisions").await.json();
+        assert_eq!(revisions.as_array().unwrap().len(), 3);
+        assert_eq!(revisions[2]["title"], "third");
+
+        // Another tenant sees no post at all
+        let other_token = app.token_for(OTHER_TENANT_EMAIL).await;
+        app.get("/api/1/revisions")
+            .bearer(&other_token)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+        app.post("/api/1/revisions/1/rollback")
+            .bearer(&other_token)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+    }
+}
@@ -0,0 +1,181 @@
+//! Permissions carried in the `scope` claim of access tokens.
+//!
+//! Every user has a set of allowed scopes (`user.scopes`); tokens carry all
+//! of them, or the subset requested at `/oauth/token`. Handlers declare what
+//! they need by taking a `RequireScope<S>` instead of `Claims`:
+//!
+//! ```ignore
+//! pub async fn api_create_post(RequireScope(claims, _): RequireScope<PostsWrite>, ...)
+//! ```
+//!
+//! The claims come from the `Claims` extractor unless another source is
+//! named, e.g. `RequireScope<PostsWrite, Session>` for the HTML pages.
+
+use std::fmt::Display;
+use std::marker::PhantomData;
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, RequestParts},
+    response::{IntoResponse, Response},
+};
+
+use crate::post_service::{AuthError, Claims};
+
+/// Scopes of new users: everything but administration.
+pub const DEFAULT_USER_SCOPES: &str = "posts:read posts:write";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    PostsRead,
+    PostsWrite,
+    UsersAdmin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::PostsRead, Scope::PostsWrite, Scope::UsersAdmin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::PostsRead => "posts:read",
+            Scope::PostsWrite => "posts:write",
+            Scope::UsersAdmin => "users:admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Scope> {
+        Self::ALL.into_iter().find(|scope| scope.as_str() == value)
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Whether the space-separated `scopes` include `scope`.
+pub fn contains(scopes: &str, scope: Scope) -> bool {
+    scopes.split(' ').any(|value| value == scope.as_str())
+}
+
+/// Whether every scope in `requested` is known and included in `allowed`.
+pub fn is_subset(requested: &str, allowed: &str) -> bool {
+    requested
+        .split(' ')
+        .all(|value| Scope::parse(value).is_some_and(|scope| contains(allowed, scope)))
+}
+
+/// Type-level name of a scope, for `RequireScope`.
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub struct PostsRead;
+
+impl RequiredScope for PostsRead {
+    const SCOPE: Scope = Scope::PostsRead;
+}
+
+pub struct PostsWrite;
+
+impl RequiredScope for PostsWrite {
+    const SCOPE: Scope = Scope::PostsWrite;
+}
+
+pub struct UsersAdmin;
+
+impl RequiredScope for UsersAdmin {
+    const SCOPE: Scope = Scope::UsersAdmin;
+}
+
+/// `Claims` of a token carrying the scope `S`, taken from the extractor `C`;
+/// answers 403 with an `insufficient_scope` challenge otherwise.
+pub struct RequireScope<S, C = Claims>(pub Claims, pub PhantomData<(S, C)>);
+
+#[async_trait]
+impl<B, S, C> FromRequest<B> for RequireScope<S, C>
+where
+    B: Send,
+    S: RequiredScope,
+    C: FromRequest<B> + Into<Claims>,
+    C::Rejection: IntoResponse,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let claims: Claims = C::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?
+            .into();
+        if !claims.has_scope(S::SCOPE) {
+            tracing::info!(user = claims.sub(), scope = %S::SCOPE, "insufficient scope");
+            return Err(AuthError::InsufficientScope(S::SCOPE).into_response());
+        }
+
+        Ok(Self(claims, PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_app::TestApp;
+
+    #[test]
+    fn scopes() {
+        assert_eq!(Scope::parse("posts:write"), Some(Scope::PostsWrite));
+        assert_eq!(Scope::parse("posts"), None);
+
+        assert!(contains(DEFAULT_USER_SCOPES, Scope::PostsRead));
+        assert!(!contains(DEFAULT_USER_SCOPES, Scope::UsersAdmin));
+        assert!(!contains("", Scope::PostsRead));
+
+        assert!(is_subset("posts:read", DEFAULT_USER_SCOPES));
+        assert!(is_subset("posts:write posts:read", DEFAULT_USER_SCOPES));
+        assert!(!is_subset("posts:read users:admin", DEFAULT_USER_SCOPES));
+        assert!(!is_subset("posts:read unknown", "posts:read unknown"));
+    }
+
+    #[tokio::test]
+    async fn read_only_token() {
+        let app = TestApp::new().await;
+        let body = app
+            .post("/oauth/token")
+            .form(
+                "grant_type=client_credentials&client_id=account%40example.com&client_secret=secret&scope=posts:read"
+                    .to_owned(),
+            )
+            .send()
+            .await
+            .assert_status(StatusCode::OK)
+            .json();
+        let token = body["access_token"].as_str().unwrap();
+
+        app.get("/api/")
+            .bearer(token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+
+        let response = app
+            .post("/api/")
+            .bearer(token)
+            .json(json!({"title": "title11", "text": "text11", "new_col": 17}))
+            .send()
+            .await
+            .assert_error(StatusCode::FORBIDDEN, "Insufficient scope");
+        assert_eq!(
+            response.header(header::WWW_AUTHENTICATE),
+            r#"Bearer error="insufficient_scope", scope="posts:write""#
+        );
+        app.delete("/api/1")
+            .bearer(token)
+            .send()
+            .await
+            .assert_error(StatusCode::FORBIDDEN, "Insufficient scope");
+    }
+}
@@ -0,0 +1,330 @@
+//! Fixture loading for development, test and demo databases.
+//!
+//! Seed data is deliberately kept out of `Migrator::migrations()` so that
+//! schema migrations never create accounts or content in production. Fixtures
+//! live in `$FIXTURES_DIR/<environment>.{json,yaml,yml}` and every record is
+//! upserted on its natural key, so seeding the same file twice is a no-op.
+//! Rows seeded with an explicit `id` move the Postgres sequences past them, so
+//! later inserts get fresh ids.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use entity::{
+    cake::{self, Entity as Cake},
+    posts::{self, Entity as Posts},
+    tenant::{self, Entity as Tenant},
+    user::{self, Entity as User},
+};
+use sea_orm::{prelude::*, ConnectionTrait, DbBackend, Set, Statement};
+use serde::Deserialize;
+
+use crate::audit;
+use crate::post_service::hash_secret;
+use crate::scope::DEFAULT_USER_SCOPES;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Fixtures {
+    tenants: Vec<TenantFixture>,
+    users: Vec<UserFixture>,
+    posts: Vec<PostFixture>,
+    cakes: Vec<CakeFixture>,
+}
+
+/// Tenants are matched on `id`; tenant 1 is created by the migrations.
+#[derive(Debug, Deserialize)]
+pub struct TenantFixture {
+    id: i32,
+    name: String,
+}
+
+/// Users are matched on `email`; the secret is hashed like `/authorize` does
+/// and only set on new users, so reseeding never resets a secret. `scopes`
+/// defaults to `scope::DEFAULT_USER_SCOPES` for new users and is
+/// left alone for existing ones.
+#[derive(Debug, Deserialize)]
+pub struct UserFixture {
+    email: String,
+    secret: String,
+    scopes: Option<String>,
+    #[serde(default = "default_tenant")]
+    tenant_id: i32,
+}
+
+/// Posts are matched on `id` so that fixtures can be re-applied.
+#[derive(Debug, Deserialize)]
+pub struct PostFixture {
+    id: i32,
+    title: String,
+    text: String,
+    #[serde(default = "default_new_col")]
+    new_col: i32,
+    #[serde(default = "default_tenant")]
+    tenant_id: i32,
+}
+
+/// Cakes are matched on `id` so that fixtures can be re-applied.
+#[derive(Debug, Deserialize)]
+pub struct CakeFixture {
+    id: i32,
+    name: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SeedReport {
+    pub inserted: usize,
+    pub updated: usize,
+}
+
+fn default_new_col() -> i32 {
+    100
+}
+
+fn default_tenant() -> i32 {
+    DEFAULT_TENANT
+}
+
+/// Tenant of users and posts that don't name one.
+pub const DEFAULT_TENANT: i32 = 1;
+
+/// The environment whose fixtures are loaded when none is given explicitly.
+pub fn current_env() -> String {
+    env::var("APP_ENV").unwrap_or_else(|_| "development".to_owned())
+}
+
+/// Find the fixtures file for `environment` in `$FIXTURES_DIR` (default `fixtures`).
+pub fn fixtures_path(environment: &str) -> anyhow::Result<PathBuf> {
+    let dir = env::var("FIXTURES_DIR").unwrap_or_else(|_| "fixtures".to_owned());
+    for ext in ["json", "yaml", "yml"] {
+        let path = Path::new(&dir).join(format!("{}.{}", environment, ext));
+        if path.is_file() {
+            return Ok(path);
+        }
+    }
+    bail!(
+        "no fixtures found for environment {:?} in {}",
+        environment,
+        dir
+    )
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Fixtures> {
+    let content = std::fs::read_to_string(path)?;
+    let fixtures = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&content)?,
+        _ => serde_json::from_str(&content)?,
+    };
+
+    Ok(fixtures)
+}
+
+// cargo run -- seed fixtures/development.yaml
+pub async fn seed_from_file(conn: &DatabaseConnection, path: &Path) -> anyhow::Result<SeedReport> {
+    let fixtures = load(path)?;
+    let report = seed(conn, fixtures).await?;
+    tracing::info!(
+        "fixtures loaded from {}: {} inserted, {} updated",
+        path.display(),
+        report.inserted,
+        report.updated
+    );
+
+    Ok(report)
+}
+
+pub async fn seed(conn: &DatabaseConnection, fixtures: Fixtures) -> Result<SeedReport, DbErr> {
+    let mut report = SeedReport::default();
+
+    for input in fixtures.tenants {
+        let exists = Tenant::find_by_id(input.id).one(conn).await?.is_some();
+        let model = tenant::ActiveModel {
+            id: Set(input.id),
+            name: Set(input.name),
+        };
+        if exists {
+            model.update(conn).await?;
+            report.updated += 1;
+        } else {
+            model.insert(conn).await?;
+            report.inserted += 1;
+        }
+    }
+
+    for input in fixtures.users {
+        let existing = User::find()
+            .filter(user::Column::Email.eq(input.email.as_str()))
+            .one(conn)
+            .await?;
+        match existing {
+            Some(before) => {
+                let mut model: user::ActiveModel = before.clone().into();
+                model.tenant_id = Set(input.tenant_id);
+                if let Some(scopes) = input.scopes {
+                    model.scopes = Set(scopes);
+                }
+                let after = model.update(conn).await?;
+                audit::updated(conn, None, &before, &after).await?;
+                report.updated += 1;
+            }
+            None => {
+                let after = user::ActiveModel {
+                    email: Set(input.email),
+                    hash: Set(hash_secret(&input.secret)),
+                    scopes: Set(input
+                        .scopes
+                        .unwrap_or_else(|| DEFAULT_USER_SCOPES.to_owned())),
+                    tenant_id: Set(input.tenant_id),
+                    ..Default::default()
+                }
+                .insert(conn)
+                .await?;
+                audit::created(conn, None, &after).await?;
+                report.inserted += 1;
+            }
+        }
+    }
+
+    for input in fixtures.posts {
+        let existing = Posts::find_by_id(input.id).one(conn).await?;
+        let model = posts::ActiveModel {
+            id: Set(input.id),
+            title: Set(input.title),
+            text: Set(input.text),
+            new_col: Set(input.new_col),
+            tenant_id: Set(input.tenant_id),
+        };
+        if let Some(before) = existing {
+            let after = model.update(conn).await?;
+            audit::updated(conn, None, &before, &after).await?;
+            report.updated += 1;
+        } else {
+            let after = model.insert(conn).await?;
+            audit::created(conn, None, &after).await?;
+            report.inserted += 1;
+        }
+    }
+
+    for input in fixtures.cakes {
+        let existing = Cake::find_by_id(input.id).one(conn).await?;
+        let model = cake::ActiveModel {
+            id: Set(input.id),
+            name: Set(input.name),
+        };
+        if let Some(before) = existing {
+            let after = model.update(conn).await?;
+            audit::updated(conn, None, &before, &after).await?;
+            report.updated += 1;
+        } else {
+            let after = model.insert(conn).await?;
+            audit::created(conn, None, &after).await?;
+            report.inserted += 1;
+        }
+    }
+
+    reset_sequence(conn, Tenant).await?;
+    reset_sequence(conn, Posts).await?;
+    reset_sequence(conn, Cake).await?;
+
+    Ok(report)
+}
+
+/// Move the Postgres sequence of `entity`'s `id` past the highest id, as rows
+/// inserted with an explicit id don't advance it. MySQL and SQLite do that on
+/// their own.
+async fn reset_sequence<E: EntityTrait>(conn: &DatabaseConnection, entity: E) -> Result<(), DbErr> {
+    if conn.get_database_backend() != DbBackend::Postgres {
+        return Ok(());
+    }
+    let sql = format!(
+        r#"SELECT setval(pg_get_serial_sequence('"{0}"', 'id'), COALESCE(MAX("id"), 0) + 1, false) FROM "{0}""#,
+        entity.table_name()
+    );
+    conn.execute(Statement::from_string(DbBackend::Postgres, sql))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use migration::{Migrator, MigratorTrait};
+    use sea_orm::Database;
+
+    #[tokio::test]
+    async fn seed_is_idempotent() {
+        let conn = Database::connect("sqlite::memory:".to_string())
+            .await
+            .expect("Database connection failed");
+        Migrator::up(&conn, None).await.unwrap();
+
+        let yaml = r#"
+posts:
+  - id: 1
+    title: title11
+    text: text11
+    new_col: 17
+cakes:
+  - id: 1
+    name: cheese
+"#;
+        let fixtures: Fixtures = serde_yaml::from_str(yaml).unwrap();
+        let report = seed(&conn, fixtures).await.unwrap();
+        assert_eq!(
+            report,
+            SeedReport {
+                inserted: 2,
+                updated: 0
+            }
+        );
+
+        let fixtures: Fixtures = serde_yaml::from_str(yaml).unwrap();
+        let report = seed(&conn, fixtures).await.unwrap();
+        assert_eq!(
+            report,
+            SeedReport {
+                inserted: 0,
+                updated: 2
+            }
+        );
+
+        let posts = Posts::find().all(&conn).await.unwrap();
+        assert_eq!(1, posts.len());
+        assert_eq!(posts[0].title, "title11");
+        assert_eq!(posts[0].new_col, 17);
+        assert_eq!(1, Cake::find().all(&conn).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn reseeding_keeps_secrets() {
+        let app = crate::test_app::TestApp::new().await;
+        let fixtures = Fixtures {
+            users: vec![UserFixture {
+                email: crate::test_app::EMAIL.to_owned(),
+                secret: "fixture secret".to_owned(),
+                scopes: None,
+                tenant_id: DEFAULT_TENANT,
+            }],
+            ..Fixtures::default()
+        };
+
+        seed(&app.conn, fixtures).await.unwrap();
+        app.authorize(crate::test_app::EMAIL, crate::test_app::SECRET)
+            .await
+            .assert_status(axum::http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn migrations_do_not_seed_users() {
+        let conn = Database::connect("sqlite::memory:".to_string())
+            .await
+            .expect("Database connection failed");
+        Migrator::up(&conn, None).await.unwrap();
+
+        assert!(User::find().all(&conn).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+//! Browser sessions for the HTML pages.
+//!
+//! Logging in through the form stores the same JWT `/authorize` hands out in
+//! an `HttpOnly`, `Secure`, `SameSite=Strict` cookie, which the `Claims`
+//! extractor accepts in place of the `Authorization` header. Form posts are
+//! protected with a double-submit CSRF token: a random value kept in a cookie
+//! that every form has to echo back in its `csrf_token` field.
+//!
+//! Set `SESSION_COOKIE_SECURE=false` to use the pages over plain HTTP on
+//! browsers that refuse secure cookies from `http://localhost`.
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, Extension, Form, FromRequest, RequestParts},
+    http::StatusCode,
+    response::{Html, Redirect},
+};
+use cookie::SameSite;
+use lazy_static::lazy_static;
+use ring::rand::{SecureRandom, SystemRandom};
+use sea_orm::DatabaseConnection;
+use serde::Deserialize;
+use tera::Tera;
+use tower_cookies::{Cookie, Cookies};
+
+use crate::flash::{get_flash_cookie, post_response, PostResponse};
+use crate::metrics::AuthSource;
+use crate::mfa;
+use crate::oauth::TokenConfig;
+use crate::post_service::{issue_token, verify_credentials, AuthError, Claims, FlashData};
+use crate::rate_limit::{self, RateLimiter};
+
+pub const SESSION_COOKIE: &str = "session";
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+lazy_static! {
+    static ref SECURE_COOKIES: bool = env::var("SESSION_COOKIE_SECURE")
+        .map(|value| value != "false" && value != "0")
+        .unwrap_or(true);
+}
+
+fn cookie(name: &'static str, value: String) -> Cookie<'static> {
+    let mut cookie = Cookie::new(name, value);
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_secure(*SECURE_COOKIES);
+    cookie.set_same_site(SameSite::Strict);
+    cookie
+}
+
+pub fn token_from_cookies(cookies: &Cookies) -> Option<String> {
+    cookies
+        .get(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_owned())
+}
+
+/// Claims of a logged in browser user, from the session cookie only;
+/// anonymous visitors are sent to the login form instead of getting the JSON
+/// error of the API.
+pub struct Session(pub Claims);
+
+#[async_trait]
+impl<B> FromRequest<B> for Session
+where
+    B: Send,
+{
+    type Rejection = Redirect;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        req.extensions()
+            .get::<Cookies>()
+            .and_then(token_from_cookies)
+            .and_then(|token| Claims::decode(&token, AuthSource::Session).ok())
+            .map(Session)
+            .ok_or_else(|| Redirect::to("/login"))
+    }
+}
+
+impl From<Session> for Claims {
+    fn from(session: Session) -> Self {
+        session.0
+    }
+}
+
+/// The CSRF token of the browser, issued on first use.
+pub struct Csrf {
+    token: String,
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for Csrf
+where
+    B: Send,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let cookies = req
+            .extensions()
+            .get::<Cookies>()
+            .cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Cookie layer is missing"))?;
+        if let Some(cookie) = cookies.get(CSRF_COOKIE) {
+            return Ok(Csrf {
+                token: cookie.value().to_owned(),
+            });
+        }
+
+        let mut bytes = [0u8; 32];
+        SystemRandom::new().fill(&mut bytes).map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not generate CSRF token",
+            )
+        })?;
+        let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        cookies.add(cookie(CSRF_COOKIE, token.clone()));
+
+        Ok(Csrf { token })
+    }
+}
+
+impl Csrf {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Check the token echoed back by a form against the cookie.
+    pub fn verify(&self, submitted: &str) -> Result<(), (StatusCode, &'static str)> {
+        ring::constant_time::verify_slices_are_equal(self.token.as_bytes(), submitted.as_bytes())
+            .map_err(|_| (StatusCode::FORBIDDEN, "Invalid CSRF token"))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    email: String,
+    secret: String,
+    /// TOTP or recovery code, for users with two-factor authentication
+    #[serde(default)]
+    code: String,
+    csrf_token: String,
+}
+
+/// Body of the forms that only carry the CSRF token, such as delete buttons.
+#[derive(Deserialize)]
+pub struct CsrfForm {
+    pub csrf_token: String,
+}
+
+pub async fn login_page(
+    Extension(ref templates): Extension<Tera>,
+    csrf: Csrf,
+    cookies: Cookies,
+) -> Result<Html<String>, (StatusCode, &'static str)> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("csrf_token", csrf.token());
+    if let Some(value) = get_flash_cookie::<FlashData>(&cookies) {
+        ctx.insert("flash", &value);
+    }
+
+    templates
+        .render("login.html.tera", &ctx)
+        .map(Html)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Template error"))
+}
+
+pub async fn login(
+    Extension(ref conn): Extension<DatabaseConnection>,
+    Extension(limiter): Extension<Arc<RateLimiter>>,
+    Extension(config): Extension<TokenConfig>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    csrf: Csrf,
+    cookies: Cookies,
+    Form(form): Form<LoginForm>,
+) -> Result<PostResponse, (StatusCode, &'static str)> {
+    csrf.verify(&form.csrf_token)?;
+
+    let ip = rate_limit::client_ip(connect_info.as_ref());
+    if limiter.check_login(&ip, &form.email).await.is_err() {
+        return Ok(post_response(
+            &cookies,
+            FlashData::error("Too many attempts, try again later"),
+            "/login",
+        ));
+    }
+    let result = verify_credentials(conn, &form.email, &form.secret).await;
+    let user = match limiter.record_login(&form.email, result).await {
+        Ok(user) => user,
+        Err(_) => {
+            return Ok(post_response(
+                &cookies,
+                FlashData::error("Wrong email or secret"),
+                "/login",
+            ))
+        }
+    };
+    if user.totp_enabled_at.is_some() {
+        let valid = mfa::check_code(conn, &user, &form.code)
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Database error"))?;
+        if !valid {
+            // Counts towards the lockout like a wrong secret
+            let _ = limiter
+                .record_login::<()>(&form.email, Err(AuthError::WrongCredentials))
+                .await;
+            return Ok(post_response(
+                &cookies,
+                FlashData::error("Wrong or missing two-factor code"),
+                "/login",
+            ));
+        }
+    }
+    let token = issue_token(&user, &config)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Token creation error"))?;
+    cookies.add(cookie(SESSION_COOKIE, token));
+
+    Ok(post_response(
+        &cookies,
+        FlashData::success("Logged in"),
+        "/",
+    ))
+}
+
+pub async fn logout(
+    csrf: Csrf,
+    cookies: Cookies,
+    Form(form): Form<CsrfForm>,
+) -> Result<PostResponse, (StatusCode, &'static str)> {
+    csrf.verify(&form.csrf_token)?;
+    cookies.remove(cookie(SESSION_COOKIE, String::new()));
+
+    Ok(post_response(
+        &cookies,
+        FlashData::success("Logged out"),
+        "/login",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use crate::test_app::TestApp;
+
+    #[tokio::test]
+    async fn login_and_logout() {
+        let app = TestApp::new().await;
+
+        let csrf_token = app.get("/login").send().await.cookie("csrf_token").unwrap();
+        let csrf = format!("csrf_token={}", csrf_token);
+        let response = app
+            .post("/login")
+            .cookie(&csrf)
+            .form(format!(
+                "email=account%40example.com&secret=wrong&csrf_token={}",
+                csrf_token
+            ))
+            .send()
+            .await
+            .assert_redirect("/login");
+        assert_eq!(response.cookie("session"), None);
+
+        app.post("/login")
+            .cookie(&csrf)
+            .form("email=account%40example.com&secret=secret&csrf_token=forged".to_owned())
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let browser = app.login().await;
+        let response = app
+            .post("/logout")
+            .cookie(&browser.cookies)
+            .form(format!("csrf_token={}", browser.csrf_token))
+            .send()
+            .await
+            .assert_redirect("/login");
+        assert_eq!(response.cookie("session").as_deref(), Some(""));
+    }
+}
@@ -0,0 +1,159 @@
+//! Coordinated shutdown of the server and its background tasks.
+//!
+//! - `SHUTDOWN_DELAY` seconds to keep accepting connections after the signal
+//!   while `/readyz` reports the instance as draining (default `0`)
+//! - `SHUTDOWN_TIMEOUT` seconds after the delay, shared by in-flight requests
+//!   and background tasks, before the process exits anyway (default `30`)
+
+use std::env;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    pub delay: Duration,
+    pub timeout: Duration,
+}
+
+impl ShutdownConfig {
+    pub fn from_env() -> Self {
+        let seconds = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .map(|value| {
+                    value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("{} must be a number of seconds", name))
+                })
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| Duration::from_secs(default))
+        };
+
+        Self {
+            delay: seconds("SHUTDOWN_DELAY", 0),
+            timeout: seconds("SHUTDOWN_TIMEOUT", 30),
+        }
+    }
+}
+
+/// Shared between the server, `/readyz` and background tasks.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    draining: Arc<AtomicBool>,
+    triggered_at: Arc<Mutex<Option<Instant>>>,
+    token: CancellationToken,
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flip readiness and notify background tasks.
+    pub fn trigger(&self) {
+        self.triggered_at
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+        self.draining.store(true, Ordering::SeqCst);
+        self.token.cancel();
+    }
+
+    /// When requests and background tasks are given up on: `config.delay`
+    /// plus `config.timeout` after the trigger, or from now if not triggered.
+    pub fn deadline(&self, config: &ShutdownConfig) -> Instant {
+        let start = self
+            .triggered_at
+            .lock()
+            .unwrap()
+            .unwrap_or_else(Instant::now);
+        start + config.delay + config.timeout
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Token cancelled when shutdown starts; background tasks should select on it.
+    pub fn token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    /// Spawn a background task that is awaited before the process exits.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handle = tokio::spawn(task);
+        self.tasks.lock().unwrap().push(handle);
+    }
+
+    /// Wait for every spawned task, giving up at `deadline`.
+    pub async fn join_tasks(&self, deadline: Instant) {
+        let tasks: Vec<_> = self.tasks.lock().unwrap().drain(..).collect();
+        let join = async {
+            for task in tasks {
+                if let Err(e) = task.await {
+                    tracing::error!("background task failed: {}", e);
+                }
+            }
+        };
+        if tokio::time::timeout_at(deadline, join).await.is_err() {
+            tracing::warn!("background tasks did not finish before the shutdown deadline");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn trigger_notifies_tasks() {
+        let shutdown = Shutdown::new();
+        let token = shutdown.token();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        shutdown.spawn(async move {
+            token.cancelled().await;
+            tx.send(()).unwrap();
+        });
+
+        assert!(!shutdown.is_draining());
+        shutdown.trigger();
+        assert!(shutdown.is_draining());
+
+        shutdown
+            .join_tasks(Instant::now() + Duration::from_secs(1))
+            .await;
+        rx.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn deadline_counts_from_the_trigger() {
+        let config = ShutdownConfig {
+            delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(2),
+        };
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        let deadline = shutdown.deadline(&config);
+        assert!(deadline <= Instant::now() + Duration::from_secs(3));
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        shutdown.trigger();
+        assert_eq!(shutdown.deadline(&config), deadline);
+
+        // A task ignoring the token is given up on at the deadline
+        shutdown.spawn(std::future::pending());
+        let start = Instant::now();
+        shutdown.join_tasks(start + Duration::from_millis(10)).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
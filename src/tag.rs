@@ -0,0 +1,275 @@
+//! Tags on posts.
+//!
+//! Tags belong to a tenant and are stored lowercase, so `Rust` and `rust` are
+//! the same tag. `PUT /api/:id/tags/:name` tags a post, creating the tag on
+//! first use, and `DELETE` removes it again; `GET /api/?tags=a,b&match=all`
+//! lists the posts carrying the tags and `GET /tags` every tag in use with
+//! its number of posts.
+
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sea_orm::DbErr;
+use serde_json::json;
+
+use crate::post_repository::{DynPostRepository, TagCount};
+use crate::post_service::FlashData;
+use crate::scope::{PostsRead, PostsWrite, RequireScope};
+
+const MAX_NAME_LEN: usize = 32;
+
+/// `name` as stored: trimmed and lowercase.
+pub fn normalize(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// Whether the normalized `name` can be a tag: letters, digits, `-` and `_`,
+/// at most 32 of them.
+fn is_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+// curl -H 'Authorization: Bearer ...' http://localhost:8000/api/1/tags
+#[utoipa::path(
+    get,
+    path = "/api/{id}/tags",
+    tag = "tags",
+    params(("id" = i32, Path, description = "Post id")),
+    responses(
+        (status = 200, description = "Names of the tags of the post, sorted", body = [String]),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:read` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:read"]))
+)]
+pub async fn list_post_tags(
+    RequireScope(claims, _): RequireScope<PostsRead>,
+    Extension(repo): Extension<DynPostRepository>,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<String>>, TagError> {
+    let tags = repo.tags(claims.tenant(), id).await?;
+
+    Ok(Json(tags))
+}
+
+// curl -X PUT -H 'Authorization: Bearer ...' http://localhost:8000/api/1/tags/rust
+#[utoipa::path(
+    put,
+    path = "/api/{id}/tags/{name}",
+    tag = "tags",
+    params(
+        ("id" = i32, Path, description = "Post id"),
+        ("name" = String, Path, description = "Tag name"),
+    ),
+    responses(
+        (status = 200, description = "Post tagged, or already was", body = FlashData),
+        (status = 400, description = "Tag name empty, too long or with other characters than letters, digits, `-` and `_`", body = ErrorBody),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:write` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:write"]))
+)]
+pub async fn add_tag(
+    RequireScope(claims, _): RequireScope<PostsWrite>,
+    Extension(repo): Extension<DynPostRepository>,
+    Path((id, name)): Path<(i32, String)>,
+) -> Result<Json<FlashData>, TagError> {
+    let name = normalize(&name);
+    if !is_valid(&name) {
+        return Err(TagError::InvalidName);
+    }
+    tracing::info!(id, tag = %name, "tagging post");
+    repo.add_tag(claims.tenant(), id, &name).await?;
+
+    Ok(Json(FlashData::success("Tag successfully added")))
+}
+
+// curl -X DELETE -H 'Authorization: Bearer ...' http://localhost:8000/api/1/tags/rust
+#[utoipa::path(
+    delete,
+    path = "/api/{id}/tags/{name}",
+    tag = "tags",
+    params(
+        ("id" = i32, Path, description = "Post id"),
+        ("name" = String, Path, description = "Tag name"),
+    ),
+    responses(
+        (status = 200, description = "Tag removed from the post", body = FlashData),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:write` scope", body = ErrorBody),
+        (status = 404, description = "No post with this id or the post lacks the tag", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:write"]))
+)]
+pub async fn remove_tag(
+    RequireScope(claims, _): RequireScope<PostsWrite>,
+    Extension(repo): Extension<DynPostRepository>,
+    Path((id, name)): Path<(i32, String)>,
+) -> Result<Json<FlashData>, TagError> {
+    let name = normalize(&name);
+    tracing::info!(id, tag = %name, "untagging post");
+    if !repo.remove_tag(claims.tenant(), id, &name).await? {
+        return Err(TagError::TagNotFound);
+    }
+
+    Ok(Json(FlashData::success("Tag successfully removed")))
+}
+
+// curl -H 'Authorization: Bearer ...' http://localhost:8000/tags
+#[utoipa::path(
+    get,
+    path = "/tags",
+    tag = "tags",
+    responses(
+        (status = 200, description = "Tags on at least one post, by name, with their number of posts", body = [TagCount]),
+        (status = 401, description = "Missing, invalid or expired token", body = ErrorBody),
+        (status = 403, description = "Token lacks the `posts:read` scope", body = ErrorBody),
+        (status = 500, description = "Database error", body = ErrorBody),
+    ),
+    security(("bearer" = ["posts:read"]))
+)]
+pub async fn list_tags(
+    RequireScope(claims, _): RequireScope<PostsRead>,
+    Extension(repo): Extension<DynPostRepository>,
+) -> Result<Json<Vec<TagCount>>, TagError> {
+    let tags = repo.tag_counts(claims.tenant()).await?;
+
+    Ok(Json(tags))
+}
+
+#[derive(Debug)]
+pub enum TagError {
+    InvalidName,
+    PostNotFound,
+    TagNotFound,
+    Database(DbErr),
+}
+
+impl From<DbErr> for TagError {
+    fn from(err: DbErr) -> Self {
+        match err {
+            DbErr::RecordNotFound(_) => Self::PostNotFound,
+            err => Self::Database(err),
+        }
+    }
+}
+
+impl IntoResponse for TagError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            TagError::InvalidName => (StatusCode::BAD_REQUEST, "Invalid tag name"),
+            TagError::PostNotFound => (StatusCode::NOT_FOUND, "Post not found"),
+            TagError::TagNotFound => (StatusCode::NOT_FOUND, "Tag not found"),
+            TagError::Database(err) => {
+                tracing::error!("database error: {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+            }
+        };
+        let body = Json(json!({
+            "error": error_message,
+        }));
+        (status, body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_app::{TestApp, OTHER_TENANT_EMAIL};
+
+    #[test]
+    fn validates_names() {
+        assert_eq!(normalize("  Rust "), "rust");
+        assert!(is_valid("sea-orm_0"));
+        assert!(is_valid("größe"));
+        assert!(is_valid(&"a".repeat(MAX_NAME_LEN)));
+        assert!(!is_valid(&"a".repeat(MAX_NAME_LEN + 1)));
+        assert!(!is_valid(""));
+        assert!(!is_valid("two words"));
+        assert!(!is_valid("a,b"));
+    }
+
+    #[tokio::test]
+    async fn post_tags() {
+        let app = TestApp::new().await;
+        let token = app.token().await;
+        let get = |uri: &str| app.get(uri).bearer(&token).send();
+        let tag = |method: Method, uri: &str| app.request(method, uri).bearer(&token).send();
+        let titles = |uri: &str| {
+            let request = app.get(uri).bearer(&token);
+            async move {
+                let page = request.send().await.assert_status(StatusCode::OK).json();
+                page["posts"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|post| post["title"].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        for title in ["first", "second"] {
+            app.post("/api/")
+                .bearer(&token)
+                .json(json!({"title": title, "text": "text", "new_col": 1}))
+                .send()
+                .await
+                .assert_status(StatusCode::OK);
+        }
+        for uri in ["/api/1/tags/Rust", "/api/1/tags/axum", "/api/2/tags/rust"] {
+            tag(Method::PUT, uri).await.assert_status(StatusCode::OK);
+        }
+        tag(Method::PUT, "/api/1/tags/two%20words")
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, "Invalid tag name");
+        tag(Method::PUT, "/api/42/tags/rust")
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+        let response = get("/api/1/tags").await.assert_status(StatusCode::OK);
+        assert_eq!(response.json(), json!(["axum", "rust"]));
+
+        assert_eq!(titles("/api/?tags=rust").await, ["first", "second"]);
+        assert_eq!(titles("/api/?tags=axum,rust&match=all").await, ["first"]);
+        assert_eq!(titles("/api/?tags=axum,web&match=any").await, ["first"]);
+        assert!(titles("/api/?tags=web").await.is_empty());
+
+        let response = get("/tags").await.assert_status(StatusCode::OK);
+        assert_eq!(
+            response.json(),
+            json!([{"name": "axum", "posts": 1}, {"name": "rust", "posts": 2}])
+        );
+
+        tag(Method::DELETE, "/api/1/tags/rust")
+            .await
+            .assert_status(StatusCode::OK);
+        tag(Method::DELETE, "/api/1/tags/rust")
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Tag not found");
+        assert_eq!(titles("/api/?tags=rust").await, ["second"]);
+
+        // Another tenant neither sees nor tags the posts
+        let other_token = app.token_for(OTHER_TENANT_EMAIL).await;
+        app.request(Method::PUT, "/api/1/tags/stolen")
+            .bearer(&other_token)
+            .send()
+            .await
+            .assert_error(StatusCode::NOT_FOUND, "Post not found");
+        let response = app.get("/tags").bearer(&other_token).send().await;
+        assert_eq!(response.json(), json!([]));
+    }
+}
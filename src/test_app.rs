@@ -0,0 +1,309 @@
+//! Harness for the HTTP tests: `app()` on a fresh in-memory database seeded
+//! from `fixtures/test.json`, driven through `tower::ServiceExt::oneshot`.
+//!
+//! ```ignore
+//! let app = TestApp::new().await;
+//! let token = app.token().await;
+//! app.get("/api/").bearer(&token).send().await.assert_status(StatusCode::OK);
+//! ```
+
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Extension,
+    http::{header, request, HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use migration::{Migrator, MigratorTrait};
+use sea_orm::DatabaseConnection;
+use serde_json::{json, Value};
+use tower::{ServiceBuilder, ServiceExt};
+
+use crate::mailer::{DynMailer, Email, InMemoryMailer};
+use crate::oauth::TokenConfig;
+use crate::post_repository::{DynPostRepository, SeaOrmPostRepository};
+use crate::rate_limit::{InMemoryStore, RateLimitConfig, RateLimiter};
+use crate::seeder;
+
+/// User seeded from `fixtures/test.json`
+pub const EMAIL: &str = "account@example.com";
+pub const SECRET: &str = "secret";
+/// User of another tenant, with the same secret
+pub const OTHER_TENANT_EMAIL: &str = "other@globex.example";
+/// User with the `users:admin` scope, in the tenant of `EMAIL`
+pub const ADMIN_EMAIL: &str = "admin@example.com";
+
+/// `JWT_SECRET` is read once per process, so every test has to agree on it.
+const JWT_SECRET: &str = "test-secret";
+
+pub struct TestApp {
+    router: Router,
+    pub conn: DatabaseConnection,
+    /// Every email the app sent
+    pub mailer: Arc<InMemoryMailer>,
+}
+
+/// Cookies of a browser logged in through the form.
+pub struct Browser {
+    pub cookies: String,
+    pub csrf_token: String,
+}
+
+impl TestApp {
+    pub async fn new() -> Self {
+        Self::with_rate_limits(RateLimitConfig::default()).await
+    }
+
+    pub async fn with_rate_limits(config: RateLimitConfig) -> Self {
+        std::env::set_var("JWT_SECRET", JWT_SECRET);
+
+        let (conn, _) = crate::db::connect("sqlite::memory:")
+            .await
+            .expect("Database connection failed");
+        Migrator::up(&conn, None).await.unwrap();
+        seeder::seed_from_file(
+            &conn,
+            Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/test.json")),
+        )
+        .await
+        .unwrap();
+
+        let posts: DynPostRepository = Arc::new(SeaOrmPostRepository::new(conn.clone()));
+        let limiter = Arc::new(RateLimiter::new(config, Arc::new(InMemoryStore::default())));
+        let mailer = Arc::new(InMemoryMailer::default());
+        let router = crate::app().layer(
+            ServiceBuilder::new()
+                .layer(Extension(conn.clone()))
+                .layer(Extension(posts))
+                .layer(Extension(mailer.clone() as DynMailer))
+                .layer(Extension(limiter))
+                .layer(Extension(TokenConfig::default())),
+        );
+
+        Self {
+            router,
+            conn,
+            mailer,
+        }
+    }
+
+    pub fn request(&self, method: Method, uri: &str) -> TestRequest {
+        TestRequest {
+            router: self.router.clone(),
+            builder: Request::builder().method(method).uri(uri),
+            body: Body::empty(),
+        }
+    }
+
+    pub fn get(&self, uri: &str) -> TestRequest {
+        self.request(Method::GET, uri)
+    }
+
+    pub fn post(&self, uri: &str) -> TestRequest {
+        self.request(Method::POST, uri)
+    }
+
+    pub fn patch(&self, uri: &str) -> TestRequest {
+        self.request(Method::PATCH, uri)
+    }
+
+    pub fn delete(&self, uri: &str) -> TestRequest {
+        self.request(Method::DELETE, uri)
+    }
+
+    pub async fn authorize(&self, email: &str, secret: &str) -> TestResponse {
+        self.post("/authorize")
+            .json(json!({"client_id": email, "client_secret": secret}))
+            .send()
+            .await
+    }
+
+    /// Bearer token of the seeded user.
+    pub async fn token(&self) -> String {
+        self.token_for(EMAIL).await
+    }
+
+    pub async fn token_for(&self, email: &str) -> String {
+        let body = self
+            .authorize(email, SECRET)
+            .await
+            .assert_status(StatusCode::OK)
+            .json();
+        body["access_token"].as_str().unwrap().to_owned()
+    }
+
+    /// Token of the seeded user that expired long ago.
+    pub fn expired_token(&self) -> String {
+        let claims = json!({
+            "sub": EMAIL,
+            "company": 1,
+            "scope": "posts:read posts:write",
+            "exp": 1,
+        });
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(JWT_SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    /// Every email sent once there are at least `count`, as some are sent in
+    /// the background.
+    pub async fn emails(&self, count: usize) -> Vec<Email> {
+        for _ in 0..500 {
+            let sent = self.mailer.sent();
+            if sent.len() >= count {
+                return sent;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("fewer than {} emails sent", count);
+    }
+
+    /// Create a personal API key with `token`.
+    pub async fn api_key(&self, token: &str) -> String {
+        let response = self
+            .post("/api-keys")
+            .bearer(token)
+            .json(json!({"name": "ci"}))
+            .send()
+            .await
+            .assert_status(StatusCode::CREATED);
+        response.json()["key"].as_str().unwrap().to_owned()
+    }
+
+    /// Log the seeded user in through the form, like a browser would.
+    pub async fn login(&self) -> Browser {
+        let response = self
+            .get("/login")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        let csrf_token = response.cookie("csrf_token").unwrap();
+        let csrf = format!("csrf_token={}", csrf_token);
+
+        let response = self
+            .post("/login")
+            .cookie(&csrf)
+            .form(format!(
+                "email={}&secret={}&csrf_token={}",
+                EMAIL.replace('@', "%40"),
+                SECRET,
+                csrf_token
+            ))
+            .send()
+            .await
+            .assert_redirect("/");
+        let session = response.cookie("session").unwrap();
+
+        Browser {
+            cookies: format!("session={}; {}", session, csrf),
+            csrf_token,
+        }
+    }
+}
+
+pub struct TestRequest {
+    router: Router,
+    builder: request::Builder,
+    body: Body,
+}
+
+impl TestRequest {
+    pub fn header(mut self, name: header::HeaderName, value: &str) -> Self {
+        self.builder = self.builder.header(name, value);
+        self
+    }
+
+    pub fn bearer(self, token: &str) -> Self {
+        self.header(header::AUTHORIZATION, &format!("Bearer {}", token))
+    }
+
+    /// Send `cookies`, formatted as in a `Cookie` header.
+    pub fn cookie(self, cookies: &str) -> Self {
+        self.header(header::COOKIE, cookies)
+    }
+
+    pub fn json(mut self, value: Value) -> Self {
+        self.body = Body::from(serde_json::to_vec(&value).unwrap());
+        self.header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+    }
+
+    pub fn form(mut self, body: String) -> Self {
+        self.body = Body::from(body);
+        self.header(
+            header::CONTENT_TYPE,
+            mime::APPLICATION_WWW_FORM_URLENCODED.as_ref(),
+        )
+    }
+
+    pub async fn send(self) -> TestResponse {
+        let request = self.builder.body(self.body).unwrap();
+        let response = self.router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        TestResponse {
+            status,
+            headers,
+            body,
+        }
+    }
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    body: Bytes,
+}
+
+impl TestResponse {
+    #[track_caller]
+    pub fn assert_status(self, status: StatusCode) -> Self {
+        assert_eq!(self.status, status, "unexpected response: {}", self.text());
+        self
+    }
+
+    /// Check for the `{"error": message}` body every API error carries.
+    #[track_caller]
+    pub fn assert_error(self, status: StatusCode, message: &str) -> Self {
+        let response = self.assert_status(status);
+        assert_eq!(response.json(), json!({ "error": message }));
+        response
+    }
+
+    #[track_caller]
+    pub fn assert_redirect(self, location: &str) -> Self {
+        let response = self.assert_status(StatusCode::SEE_OTHER);
+        assert_eq!(response.header(header::LOCATION), location);
+        response
+    }
+
+    #[track_caller]
+    pub fn header(&self, name: header::HeaderName) -> &str {
+        self.headers[name].to_str().unwrap()
+    }
+
+    /// Value of the cookie `name` set by the response.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok()?.split(';').next())
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+            .map(str::to_owned)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    #[track_caller]
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
@@ -0,0 +1,389 @@
+//! Server-rendered HTML pages for browsing and editing posts.
+//!
+//! The pages use the same `PostRepository` as the JSON API in `post_service` and
+//! report the outcome of form posts with a `FlashData` flash cookie. They
+//! require a browser session (see `session`), the forms that change posts the
+//! `posts:write` scope of that session, and every form carries the CSRF token.
+
+use axum::{
+    extract::{Extension, Form, Path, Query},
+    http::StatusCode,
+    response::Html,
+};
+use entity::posts;
+use serde::Deserialize;
+use tera::Tera;
+use tower_cookies::Cookies;
+
+use crate::flash::{get_flash_cookie, post_response, PostResponse};
+use crate::post_repository::DynPostRepository;
+use crate::post_service::{FlashData, Params};
+use crate::scope::{PostsWrite, RequireScope};
+use crate::session::{Csrf, CsrfForm, Session};
+
+type PageResult<T> = Result<T, (StatusCode, &'static str)>;
+
+#[derive(Deserialize)]
+pub struct PostForm {
+    title: String,
+    text: String,
+    new_col: i32,
+    csrf_token: String,
+}
+
+impl From<PostForm> for posts::Model {
+    fn from(form: PostForm) -> Self {
+        Self {
+            id: 0,
+            title: form.title,
+            text: form.text,
+            new_col: form.new_col,
+            tenant_id: 0,
+        }
+    }
+}
+
+/// Context shared by every page: the logged in user, the CSRF token for the
+/// forms and the pending flash message.
+fn context(session: &Session, csrf: &Csrf, cookies: &Cookies) -> tera::Context {
+    let mut ctx = tera::Context::new();
+    ctx.insert("user", session.0.sub());
+    ctx.insert("csrf_token", csrf.token());
+    if let Some(value) = get_flash_cookie::<FlashData>(cookies) {
+        ctx.insert("flash", &value);
+    }
+    ctx
+}
+
+fn render(templates: &Tera, name: &str, ctx: &tera::Context) -> PageResult<Html<String>> {
+    templates.render(name, ctx).map(Html).map_err(|e| {
+        tracing::error!("could not render {}: {}", name, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Template error")
+    })
+}
+
+fn db_error(e: sea_orm::DbErr) -> (StatusCode, &'static str) {
+    if let sea_orm::DbErr::RecordNotFound(_) = e {
+        return (StatusCode::NOT_FOUND, "Post not found");
+    }
+    tracing::error!("database error: {}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+}
+
+fn not_found(templates: &Tera, ctx: &tera::Context) -> PageResult<(StatusCode, Html<String>)> {
+    let body = render(templates, "error/404.html.tera", ctx)?;
+    Ok((StatusCode::NOT_FOUND, body))
+}
+
+pub async fn web_list_posts(
+    session: Session,
+    csrf: Csrf,
+    Extension(ref templates): Extension<Tera>,
+    Extension(repo): Extension<DynPostRepository>,
+    Query(params): Query<Params>,
+    cookies: Cookies,
+) -> PageResult<Html<String>> {
+    let page = repo
+        .list(
+            session.0.tenant(),
+            &params.tag_filter(),
+            params.page(),
+            params.posts_per_page(),
+        )
+        .await
+        .map_err(db_error)?;
+
+    let mut ctx = context(&session, &csrf, &cookies);
+    ctx.insert("pagination", &page);
+
+    render(templates, "index.html.tera", &ctx)
+}
+
+pub async fn web_view_post(
+    session: Session,
+    csrf: Csrf,
+    Extension(ref templates): Extension<Tera>,
+    Extension(repo): Extension<DynPostRepository>,
+    Path(id): Path<i32>,
+    cookies: Cookies,
+) -> PageResult<(StatusCode, Html<String>)> {
+    let mut ctx = context(&session, &csrf, &cookies);
+    let post = match repo.get(session.0.tenant(), id).await.map_err(db_error)? {
+        Some(post) => post,
+        None => return not_found(templates, &ctx),
+    };
+    ctx.insert("post", &post);
+
+    Ok((StatusCode::OK, render(templates, "view.html.tera", &ctx)?))
+}
+
+pub async fn web_new_post(
+    session: Session,
+    csrf: Csrf,
+    Extension(ref templates): Extension<Tera>,
+    cookies: Cookies,
+) -> PageResult<Html<String>> {
+    let ctx = context(&session, &csrf, &cookies);
+
+    render(templates, "new.html.tera", &ctx)
+}
+
+pub async fn web_create_post(
+    RequireScope(claims, _): RequireScope<PostsWrite, Session>,
+    csrf: Csrf,
+    Extension(repo): Extension<DynPostRepository>,
+    cookies: Cookies,
+    Form(form): Form<PostForm>,
+) -> PageResult<PostResponse> {
+    csrf.verify(&form.csrf_token)?;
+    let post = repo
+        .create(claims.tenant(), claims.sub(), form.into())
+        .await
+        .map_err(db_error)?;
+
+    Ok(post_response(
+        &cookies,
+        FlashData::success("Post succcessfully added"),
+        &format!("/posts/{}", post.id),
+    ))
+}
+
+pub async fn web_edit_post(
+    session: Session,
+    csrf: Csrf,
+    Extension(ref templates): Extension<Tera>,
+    Extension(repo): Extension<DynPostRepository>,
+    Path(id): Path<i32>,
+    cookies: Cookies,
+) -> PageResult<(StatusCode, Html<String>)> {
+    let mut ctx = context(&session, &csrf, &cookies);
+    let post = match repo.get(session.0.tenant(), id).await.map_err(db_error)? {
+        Some(post) => post,
+        None => return not_found(templates, &ctx),
+    };
+    ctx.insert("post", &post);
+
+    Ok((StatusCode::OK, render(templates, "edit.html.tera", &ctx)?))
+}
+
+pub async fn web_update_post(
+    RequireScope(claims, _): RequireScope<PostsWrite, Session>,
+    csrf: Csrf,
+    Extension(repo): Extension<DynPostRepository>,
+    Path(id): Path<i32>,
+    cookies: Cookies,
+    Form(form): Form<PostForm>,
+) -> PageResult<PostResponse> {
+    csrf.verify(&form.csrf_token)?;
+    repo.update(claims.tenant(), claims.sub(), id, form.into())
+        .await
+        .map_err(db_error)?;
+
+    Ok(post_response(
+        &cookies,
+        FlashData::success("Post succcessfully updated"),
+        &format!("/posts/{}", id),
+    ))
+}
+
+pub async fn web_delete_post(
+    RequireScope(claims, _): RequireScope<PostsWrite, Session>,
+    csrf: Csrf,
+    Extension(repo): Extension<DynPostRepository>,
+    Path(id): Path<i32>,
+    cookies: Cookies,
+    Form(form): Form<CsrfForm>,
+) -> PageResult<PostResponse> {
+    csrf.verify(&form.csrf_token)?;
+    repo.delete(claims.tenant(), claims.sub(), id)
+        .await
+        .map_err(db_error)?;
+
+    Ok(post_response(
+        &cookies,
+        FlashData::success("Post succcessfully deleted"),
+        "/",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use sea_orm::EntityTrait;
+    use serde_json::json;
+
+    use crate::test_app::TestApp;
+
+    fn post_form(title: &str, csrf_token: &str) -> String {
+        format!(
+            "title={}&text=text11&new_col=17&csrf_token={}",
+            title, csrf_token
+        )
+    }
+
+    #[tokio::test]
+    async fn html_pages() {
+        let app = TestApp::new().await;
+
+        app.get("/static/css/style.css")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+
+        // Anonymous visitors are sent to the login form
+        for uri in ["/", "/new", "/posts/1", "/posts/1/edit"] {
+            app.get(uri).send().await.assert_redirect("/login");
+        }
+
+        let browser = app.login().await;
+
+        app.get("/")
+            .cookie(&browser.cookies)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        let response = app
+            .get("/new")
+            .cookie(&browser.cookies)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(response.text().contains(&browser.csrf_token));
+
+        // Form posts without the CSRF token are refused
+        app.post("/posts")
+            .cookie(&browser.cookies)
+            .form(post_form("title11", "forged"))
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let response = app
+            .post("/posts")
+            .cookie(&browser.cookies)
+            .form(post_form("title11", &browser.csrf_token))
+            .send()
+            .await
+            .assert_redirect("/posts/1");
+        let flash = format!(
+            "_flash={}; {}",
+            response.cookie("_flash").unwrap(),
+            browser.cookies
+        );
+
+        let response = app
+            .get("/posts/1")
+            .cookie(&flash)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(response.text().contains("title11"));
+        assert!(response.text().contains("Post succcessfully added"));
+
+        let response = app
+            .get("/posts/1/edit")
+            .cookie(&browser.cookies)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(response.text().contains("title11"));
+
+        app.post("/posts/1")
+            .cookie(&browser.cookies)
+            .form(post_form("updated", &browser.csrf_token))
+            .send()
+            .await
+            .assert_redirect("/posts/1");
+        let response = app
+            .get("/")
+            .cookie(&browser.cookies)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(response.text().contains("updated"));
+
+        for uri in ["/posts/42", "/posts/42/edit"] {
+            app.get(uri)
+                .cookie(&browser.cookies)
+                .send()
+                .await
+                .assert_status(StatusCode::NOT_FOUND);
+        }
+        app.post("/posts/42")
+            .cookie(&browser.cookies)
+            .form(post_form("updated", &browser.csrf_token))
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        app.post("/posts/1/delete")
+            .cookie(&browser.cookies)
+            .form(format!("csrf_token={}", browser.csrf_token))
+            .send()
+            .await
+            .assert_redirect("/");
+        app.get("/posts/1")
+            .cookie(&browser.cookies)
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn web_pages_escape_posts() {
+        let app = TestApp::new().await;
+        app.post("/api/")
+            .bearer(&app.token().await)
+            .json(json!({"title": "<script>alert(1)</script>", "text": "a & b", "new_col": 1}))
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+
+        let browser = app.login().await;
+        for uri in ["/", "/posts/1", "/posts/1/edit"] {
+            let page = app
+                .get(uri)
+                .cookie(&browser.cookies)
+                .send()
+                .await
+                .assert_status(StatusCode::OK)
+                .text();
+            assert!(!page.contains("<script>"), "{} is not escaped", uri);
+            assert!(page.contains("&lt;script&gt;alert(1)&lt;&#x2F;script&gt;"));
+            assert!(page.contains("a &amp; b"));
+        }
+    }
+
+    #[tokio::test]
+    async fn web_forms_need_session_with_write_scope() {
+        let app = TestApp::new().await;
+
+        // Bearer tokens are for the API, the pages only take the session
+        app.get("/")
+            .bearer(&app.token().await)
+            .send()
+            .await
+            .assert_redirect("/login");
+
+        entity::user::Entity::update_many()
+            .col_expr(
+                entity::user::Column::Scopes,
+                sea_orm::sea_query::Expr::value("posts:read"),
+            )
+            .exec(&app.conn)
+            .await
+            .unwrap();
+        let browser = app.login().await;
+        app.get("/")
+            .cookie(&browser.cookies)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+        app.post("/posts")
+            .cookie(&browser.cookies)
+            .form(post_form("title11", &browser.csrf_token))
+            .send()
+            .await
+            .assert_error(StatusCode::FORBIDDEN, "Insufficient scope");
+    }
+}